@@ -0,0 +1,362 @@
+use crate::options::{as_datetime, as_string, as_timezone, invalid};
+use crate::tz::resolve_local;
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, NaiveTime, Utc, Weekday};
+use chrono_tz::Tz;
+use mlua::prelude::*;
+use std::collections::HashSet;
+
+/// `sched:calendar` 注册的日历，挂到任务上之后落在里面的触发会被跳过或者顺延
+pub struct Calendar {
+    pub name: String,
+    /// 按这个时区判断星期和日期，为空时使用本机时区
+    timezone: Option<Tz>,
+    weekdays: HashSet<Weekday>,
+    dates: HashSet<NaiveDate>,
+    ranges: Vec<Blackout>,
+}
+
+/// 一段不执行的时间，左闭右开
+struct Blackout {
+    name: String,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+}
+
+/// 触发时间被日历排除的原因，以及排除到什么时候为止
+pub struct Exclusion {
+    pub reason: String,
+    pub until: DateTime<Utc>,
+}
+
+impl Calendar {
+    pub fn from_table(name: String, table: LuaTable, timezone: Option<Tz>) -> LuaResult<Self> {
+        let mut calendar = Calendar {
+            name,
+            timezone,
+            weekdays: HashSet::new(),
+            dates: HashSet::new(),
+            ranges: Vec::new(),
+        };
+        // 日期和时间段按日历时区解析，所以先处理 timezone
+        let timezone: LuaValue = table.get("timezone")?;
+        if timezone != LuaValue::Nil {
+            calendar.timezone = Some(as_timezone("timezone", timezone)?);
+        }
+
+        for pair in table.pairs::<String, LuaValue>() {
+            let (key, value) = pair?;
+            match key.as_str() {
+                "timezone" => {}
+                "weekdays" => {
+                    for value in as_list(value)? {
+                        let weekday = as_string(&key, value)?;
+                        let weekday = weekday
+                            .parse::<Weekday>()
+                            .map_err(|_| invalid(&key, format!("unknown weekday `{weekday}`")))?;
+                        calendar.weekdays.insert(weekday);
+                    }
+                }
+                "dates" => {
+                    for value in as_list(value)? {
+                        let date = as_string(&key, value)?;
+                        calendar
+                            .dates
+                            .insert(parse_date(&date).map_err(|e| invalid(&key, e))?);
+                    }
+                }
+                "file" => {
+                    let path = as_string(&key, value)?;
+                    let content = std::fs::read_to_string(&path)
+                        .map_err(|e| invalid(&key, format!("failed to read `{path}`: {e}")))?;
+                    let dates = if path.ends_with(".ics") || content.starts_with("BEGIN:VCALENDAR")
+                    {
+                        parse_ics(&content, calendar.timezone)
+                    } else {
+                        parse_date_list(&content)
+                    };
+                    calendar
+                        .dates
+                        .extend(dates.map_err(|e| invalid(&key, format!("{path}: {e}")))?);
+                }
+                "ranges" => {
+                    let ranges = match value {
+                        LuaValue::Table(ranges) => ranges,
+                        _ => return Err(invalid(&key, "expected a table of {from, to} pairs")),
+                    };
+                    for pair in ranges.pairs::<LuaValue, LuaTable>() {
+                        let (name, range) = pair?;
+                        let name = match name {
+                            LuaValue::String(name) => name.to_str()?.to_string(),
+                            LuaValue::Integer(i) => format!("range {i}"),
+                            _ => return Err(invalid(&key, "expected range names or an array")),
+                        };
+                        let from = as_datetime(&key, range.get(1)?, calendar.timezone)?;
+                        let to = as_datetime(&key, range.get(2)?, calendar.timezone)?;
+                        if to <= from {
+                            return Err(invalid(
+                                &key,
+                                format!("`{name}` must end after it starts"),
+                            ));
+                        }
+                        calendar.ranges.push(Blackout { name, from, to });
+                    }
+                }
+                _ => return Err(invalid(&key, "unknown option")),
+            }
+        }
+
+        if calendar.weekdays.len() == 7 {
+            return Err(invalid("weekdays", "cannot exclude every day of the week"));
+        }
+        Ok(calendar)
+    }
+
+    /// `datetime` 落在日历里时返回原因
+    pub fn excludes(&self, datetime: DateTime<Utc>) -> Option<Exclusion> {
+        if let Some(range) = self
+            .ranges
+            .iter()
+            .find(|range| range.from <= datetime && datetime < range.to)
+        {
+            return Some(Exclusion {
+                reason: format!("blackout `{}` in calendar `{}`", range.name, self.name),
+                until: range.to,
+            });
+        }
+        let date = match self.timezone {
+            Some(tz) => datetime.with_timezone(&tz).date_naive(),
+            None => datetime.with_timezone(&chrono::Local).date_naive(),
+        };
+        let reason = if self.weekdays.contains(&date.weekday()) {
+            format!("{} is excluded by calendar `{}`", date.weekday(), self.name)
+        } else if self.dates.contains(&date) {
+            format!("{} is a holiday in calendar `{}`", date, self.name)
+        } else {
+            return None;
+        };
+        let next_day = (date + Duration::days(1)).and_hms_opt(0, 0, 0)?;
+        Some(Exclusion {
+            reason,
+            until: resolve_local(next_day, self.timezone),
+        })
+    }
+}
+
+fn as_list(value: LuaValue) -> LuaResult<Vec<LuaValue>> {
+    match value {
+        LuaValue::Table(table) => table.sequence_values().collect(),
+        value => Ok(vec![value]),
+    }
+}
+
+fn parse_date(value: &str) -> Result<NaiveDate, String> {
+    NaiveDate::parse_from_str(value.trim(), "%Y-%m-%d")
+        .map_err(|_| format!("invalid date `{}`", value.trim()))
+}
+
+/// 每行一个 `YYYY-MM-DD`，后面可以跟说明，`#` 开头的是注释
+fn parse_date_list(content: &str) -> Result<Vec<NaiveDate>, String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| parse_date(line.split_whitespace().next().unwrap_or(line)))
+        .collect()
+}
+
+/// 只取每个 VEVENT 的 DTSTART / DTEND，DTEND 不包含在内，带时刻的按日历时区换算成日期
+fn parse_ics(content: &str, timezone: Option<Tz>) -> Result<Vec<NaiveDate>, String> {
+    let mut dates = Vec::new();
+    let mut start = None;
+    let mut end: Option<NaiveDateTime> = None;
+    for line in unfold(content) {
+        if line == "BEGIN:VEVENT" {
+            start = None;
+            end = None;
+        } else if line == "END:VEVENT" {
+            let (start, all_day) = start.ok_or("VEVENT without DTSTART")?;
+            let end = end.unwrap_or(if all_day {
+                start + Duration::days(1)
+            } else {
+                start
+            });
+            // 结束在零点时那一天不算
+            let last = if end.time() == NaiveTime::MIN {
+                end.date() - Duration::days(1)
+            } else {
+                end.date()
+            };
+            let mut date = start.date();
+            while date <= last.max(start.date()) {
+                dates.push(date);
+                date += Duration::days(1);
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            let mut params = name.split(';');
+            let field = params.next().unwrap_or(name);
+            match field {
+                "DTSTART" | "DTEND" => {
+                    let datetime = parse_ics_datetime(params, value, timezone)
+                        .map_err(|e| format!("invalid {field} `{value}`: {e}"))?;
+                    if field == "DTSTART" {
+                        start = Some(datetime);
+                    } else {
+                        end = Some(datetime.0);
+                    }
+                }
+                // 重复的事件只会生效第一次，与其悄悄漏掉不如直接报错
+                "RRULE" | "RDATE" => {
+                    return Err(format!(
+                        "{field} is not supported, list every date as its own event"
+                    ))
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(dates)
+}
+
+/// 把折行（以空格或 tab 开头的行）接回上一行
+fn unfold(content: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for line in content.lines() {
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(rest), Some(last)) => last.push_str(rest),
+            _ => lines.push(line.trim_end().to_string()),
+        }
+    }
+    lines
+}
+
+/// `20261001`、`20261001T090000Z`、`TZID=Europe/Berlin:20261001T090000` 换算成日历时区的本地时间，
+/// 另外返回是不是全天
+fn parse_ics_datetime<'a>(
+    mut params: impl Iterator<Item = &'a str>,
+    value: &str,
+    timezone: Option<Tz>,
+) -> Result<(NaiveDateTime, bool), String> {
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y%m%d") {
+        return Ok((date.and_time(NaiveTime::MIN), true));
+    }
+    let (naive, utc) = match value.strip_suffix('Z') {
+        Some(value) => (value, true),
+        None => (value, false),
+    };
+    let naive = NaiveDateTime::parse_from_str(naive, "%Y%m%dT%H%M%S")
+        .map_err(|_| "expected YYYYMMDD or YYYYMMDDTHHMMSS".to_string())?;
+    let datetime = if utc {
+        naive.and_utc()
+    } else {
+        match params.find_map(|param| param.strip_prefix("TZID=")) {
+            Some(tzid) => {
+                let tz = tzid
+                    .trim_matches('"')
+                    .parse::<Tz>()
+                    .map_err(|_| format!("unknown time zone `{tzid}`"))?;
+                resolve_local(naive, Some(tz))
+            }
+            // 没有时区的按日历时区算
+            None => return Ok((naive, false)),
+        }
+    };
+    let local = match timezone {
+        Some(tz) => datetime.with_timezone(&tz).naive_local(),
+        None => datetime.with_timezone(&chrono::Local).naive_local(),
+    };
+    Ok((local, false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(value: &str) -> NaiveDate {
+        parse_date(value).unwrap()
+    }
+
+    fn ics(events: &str) -> String {
+        format!("BEGIN:VCALENDAR\r\nVERSION:2.0\r\n{events}END:VCALENDAR\r\n")
+    }
+
+    #[test]
+    fn date_list() {
+        let content = "# holidays\n\n2026-10-01 National Day\n  2026-10-02\n";
+        assert_eq!(
+            parse_date_list(content).unwrap(),
+            [date("2026-10-01"), date("2026-10-02")]
+        );
+        assert_eq!(
+            parse_date_list("2026-10-01\n10/02/2026\n").unwrap_err(),
+            "invalid date `10/02/2026`"
+        );
+    }
+
+    #[test]
+    fn all_day_events() {
+        let content = ics(concat!(
+            "BEGIN:VEVENT\r\nDTSTART;VALUE=DATE:20261001\r\nDTEND;VALUE=DATE:20261004\r\nEND:VEVENT\r\n",
+            "BEGIN:VEVENT\r\nDTSTART;VALUE=DATE:20261225\r\nEND:VEVENT\r\n",
+        ));
+        assert_eq!(
+            parse_ics(&content, None).unwrap(),
+            [
+                date("2026-10-01"),
+                date("2026-10-02"),
+                date("2026-10-03"),
+                date("2026-12-25"),
+            ]
+        );
+    }
+
+    #[test]
+    fn timed_events_use_the_calendar_time_zone() {
+        let shanghai = Some(chrono_tz::Asia::Shanghai);
+        // UTC 的 17:00 是上海第二天凌晨
+        let content = ics(
+            "BEGIN:VEVENT\r\nDTSTART:20261001T170000Z\r\nDTEND:20261001T180000Z\r\nEND:VEVENT\r\n",
+        );
+        assert_eq!(parse_ics(&content, shanghai).unwrap(), [date("2026-10-02")]);
+
+        let content = ics(concat!(
+            "BEGIN:VEVENT\r\nDTSTART;TZID=America/New_York:20261001T220000\r\n",
+            "DTEND;TZID=America/New_York:20261002T220000\r\nEND:VEVENT\r\n",
+        ));
+        assert_eq!(
+            parse_ics(&content, shanghai).unwrap(),
+            [date("2026-10-02"), date("2026-10-03")]
+        );
+
+        // 没有时区的按日历时区
+        let content = ics("BEGIN:VEVENT\r\nDTSTART:20261001T090000\r\nEND:VEVENT\r\n");
+        assert_eq!(parse_ics(&content, shanghai).unwrap(), [date("2026-10-01")]);
+    }
+
+    #[test]
+    fn folded_lines() {
+        let content = ics(
+            "BEGIN:VEVENT\r\nSUMMARY:National\r\n  Day\r\nDTSTART;VALUE=\r\n DATE:20261001\r\nEND:VEVENT\r\n",
+        );
+        assert_eq!(parse_ics(&content, None).unwrap(), [date("2026-10-01")]);
+    }
+
+    #[test]
+    fn invalid_events() {
+        let content = ics(
+            "BEGIN:VEVENT\r\nDTSTART;VALUE=DATE:20260101\r\nRRULE:FREQ=YEARLY\r\nEND:VEVENT\r\n",
+        );
+        assert_eq!(
+            parse_ics(&content, None).unwrap_err(),
+            "RRULE is not supported, list every date as its own event"
+        );
+        let content = ics("BEGIN:VEVENT\r\nSUMMARY:x\r\nEND:VEVENT\r\n");
+        assert_eq!(
+            parse_ics(&content, None).unwrap_err(),
+            "VEVENT without DTSTART"
+        );
+        let content = ics("BEGIN:VEVENT\r\nDTSTART:2026-10-01\r\nEND:VEVENT\r\n");
+        assert!(parse_ics(&content, None)
+            .unwrap_err()
+            .starts_with("invalid DTSTART `2026-10-01`"));
+    }
+}
@@ -0,0 +1,47 @@
+use crate::job::JobState;
+use chrono::{DateTime, Utc};
+use mlua::prelude::*;
+use std::{rc::Rc, time::Instant};
+
+/// 传给任务函数的参数，描述正在执行的是哪一次触发
+///
+/// 每次调用（包括重试）都会新建一个，`attempt` 从 1 开始。
+#[derive(Clone)]
+pub struct RunContext {
+    pub state: Rc<JobState>,
+    pub run_id: u64,
+    pub scheduled_at: DateTime<Utc>,
+    pub started_at: DateTime<Utc>,
+    pub attempt: u32,
+    /// 这次执行开始前最后一次成功执行的触发时间
+    pub last_success: Option<DateTime<Utc>>,
+    pub deadline: Option<Instant>,
+}
+
+impl RunContext {
+    /// 任务被取消或者这次执行已经超时，长时间运行的任务应该尽快返回
+    pub fn is_cancelled(&self) -> bool {
+        self.state.is_cancelled()
+            || self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+}
+
+impl LuaUserData for RunContext {
+    fn add_fields<'lua, F: LuaUserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("job", |_, this| Ok(this.state.name.clone()));
+        fields.add_field_method_get("job_id", |_, this| Ok(this.state.id));
+        fields.add_field_method_get("run_id", |_, this| Ok(this.run_id));
+        fields.add_field_method_get("scheduled_at", |_, this| Ok(this.scheduled_at.timestamp()));
+        fields.add_field_method_get("started_at", |_, this| Ok(this.started_at.timestamp()));
+        fields.add_field_method_get("attempt", |_, this| Ok(this.attempt));
+        fields.add_field_method_get("last_success", |_, this| {
+            Ok(this.last_success.map(|datetime| datetime.timestamp()))
+        });
+    }
+
+    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(_methods: &mut M) {
+        _methods.add_method("is_cancelled", |_, this, ()| Ok(this.is_cancelled()));
+    }
+}
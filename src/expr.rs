@@ -0,0 +1,896 @@
+//! cron 表达式前端
+//!
+//! 支持这几种写法，最后都交给 `cron::Schedule` 计算：
+//! - 5 段 Unix crontab：`分 时 日 月 周`，周日是 0 或 7；日和周都有限制时满足任意一个就触发；
+//! - 6 / 7 段 Quartz：`秒 分 时 日 月 周 [年]`，周日是 1；
+//! - `@yearly`、`@monthly`、`@weekly`、`@daily`、`@hourly`、`@reboot`；
+//! - 日字段的 `L`、`L-3`、`LW`、`15W`，周字段的 `5L`、`6#3`。
+//!
+//! `cron` 不支持的日/周写法会把这两个字段换成 `*`，再按日期过滤。
+
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use cron::Schedule;
+use std::{collections::BTreeSet, str::FromStr};
+
+pub enum Expression {
+    Cron(Box<CronExpr>),
+    /// `@reboot`，启动时执行一次
+    Reboot,
+}
+
+pub struct CronExpr {
+    schedule: Schedule,
+    days: Option<DayFilter>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dialect {
+    Unix,
+    Quartz,
+}
+
+struct Field {
+    name: &'static str,
+    min: u32,
+    max: u32,
+    /// 每个值可以用的名字，第一个是交给 cron 的写法
+    names: &'static [&'static [&'static str]],
+    /// `names[0]` 对应的值
+    name_base: u32,
+}
+
+// 和 cron 接受的名字保持一致
+const MONTH_NAMES: &[&[&str]] = &[
+    &["JAN", "JANUARY"],
+    &["FEB", "FEBRUARY"],
+    &["MAR", "MARCH"],
+    &["APR", "APRIL"],
+    &["MAY"],
+    &["JUN", "JUNE"],
+    &["JUL", "JULY"],
+    &["AUG", "AUGUST"],
+    &["SEP", "SEPTEMBER"],
+    &["OCT", "OCTOBER"],
+    &["NOV", "NOVEMBER"],
+    &["DEC", "DECEMBER"],
+];
+const WEEKDAY_NAMES: &[&[&str]] = &[
+    &["SUN", "SUNDAY"],
+    &["MON", "MONDAY"],
+    &["TUE", "TUES", "TUESDAY"],
+    &["WED", "WEDNESDAY"],
+    &["THU", "THURS", "THURSDAY"],
+    &["FRI", "FRIDAY"],
+    &["SAT", "SATURDAY"],
+];
+
+const SECOND: Field = Field::numeric("second", 0, 59);
+const MINUTE: Field = Field::numeric("minute", 0, 59);
+const HOUR: Field = Field::numeric("hour", 0, 23);
+const DAY_OF_MONTH: Field = Field::numeric("day-of-month", 1, 31);
+const YEAR: Field = Field::numeric("year", 1970, 2100);
+const MONTH: Field = Field {
+    name: "month",
+    min: 1,
+    max: 12,
+    names: MONTH_NAMES,
+    name_base: 1,
+};
+
+impl Field {
+    const fn numeric(name: &'static str, min: u32, max: u32) -> Self {
+        Field {
+            name,
+            min,
+            max,
+            names: &[],
+            name_base: 0,
+        }
+    }
+
+    /// Unix 里周日是 0 或 7，Quartz 里周日是 1
+    fn day_of_week(dialect: Dialect) -> Self {
+        let (min, max, name_base) = match dialect {
+            Dialect::Unix => (0, 7, 0),
+            Dialect::Quartz => (1, 7, 1),
+        };
+        Field {
+            name: "day-of-week",
+            min,
+            max,
+            names: WEEKDAY_NAMES,
+            name_base,
+        }
+    }
+
+    fn value(&self, text: &str) -> Result<u32, String> {
+        let value = match text.parse::<u32>() {
+            Ok(value) => value,
+            Err(_) => self
+                .names
+                .iter()
+                .position(|names| names.iter().any(|name| name.eq_ignore_ascii_case(text)))
+                .map(|i| i as u32 + self.name_base)
+                .ok_or_else(|| format!("invalid value `{text}`"))?,
+        };
+        if value < self.min || value > self.max {
+            return Err(format!(
+                "value {value} out of range {}-{}",
+                self.min, self.max
+            ));
+        }
+        Ok(value)
+    }
+
+    /// 展开 `*`、`5`、`1-5`、`*/15`、`10/5`、`1-30/2` 以及逗号列表
+    fn values(&self, text: &str) -> Result<BTreeSet<u32>, String> {
+        let mut values = BTreeSet::new();
+        for item in text.split(',') {
+            let (range, step) = match item.split_once('/') {
+                Some((range, step)) => match step.parse::<u32>() {
+                    Ok(step) if step > 0 => (range, Some(step)),
+                    _ => return Err(format!("invalid step `{step}`")),
+                },
+                None => (item, None),
+            };
+            let (start, end) = if range == "*" || (range == "?" && self.allows_question_mark()) {
+                (self.min, self.max)
+            } else if let Some((start, end)) = range.split_once('-') {
+                (self.value(start)?, self.value(end)?)
+            } else {
+                let value = self.value(range)?;
+                (value, if step.is_some() { self.max } else { value })
+            };
+            if start > end {
+                return Err(format!("range `{range}` starts after it ends"));
+            }
+            values.extend((start..=end).step_by(step.unwrap_or(1) as usize));
+        }
+        Ok(values)
+    }
+
+    fn allows_question_mark(&self) -> bool {
+        self.name == DAY_OF_MONTH.name || self.name == "day-of-week"
+    }
+}
+
+fn is_restricted(text: &str) -> bool {
+    text != "*" && text != "?"
+}
+
+/// 解析 cron 表达式，出错时指出是哪一段
+pub fn parse(expression: &str) -> Result<Expression, String> {
+    parse_fields(expression.trim())
+        .map_err(|e| format!("invalid cron expression `{}`: {}", expression.trim(), e))
+}
+
+/// 展开宏并补齐成 6 或 7 段，`@reboot` 返回 `None`
+fn split(expression: &str) -> Result<Option<(Dialect, Vec<String>)>, String> {
+    if let Some(name) = expression.strip_prefix('@') {
+        let expanded = match name.to_ascii_lowercase().as_str() {
+            "reboot" => return Ok(None),
+            "yearly" | "annually" => "0 0 0 1 1 *",
+            "monthly" => "0 0 0 1 * *",
+            "weekly" => "0 0 0 * * SUN",
+            "daily" | "midnight" => "0 0 0 * * *",
+            "hourly" => "0 0 * * * *",
+            _ => return Err(format!("unknown macro `@{name}`")),
+        };
+        return split(expanded);
+    }
+
+    let fields: Vec<String> = expression.split_whitespace().map(String::from).collect();
+    match fields.len() {
+        5 => Ok(Some((
+            Dialect::Unix,
+            [vec!["0".to_string()], fields].concat(),
+        ))),
+        6 | 7 => Ok(Some((Dialect::Quartz, fields))),
+        n => Err(format!("expected 5, 6 or 7 fields, got {n}")),
+    }
+}
+
+fn parse_fields(expression: &str) -> Result<Expression, String> {
+    let (dialect, fields) = match split(expression)? {
+        Some(split) => split,
+        None => return Ok(Expression::Reboot),
+    };
+    let fields: Vec<&str> = fields.iter().map(String::as_str).collect();
+    let check = |field: &Field, text: &str| {
+        field
+            .values(text)
+            .map_err(|e| format!("{} field `{}`: {}", field.name, text, e))
+    };
+    check(&SECOND, fields[0])?;
+    check(&MINUTE, fields[1])?;
+    check(&HOUR, fields[2])?;
+    check(&MONTH, fields[4])?;
+    if let Some(year) = fields.get(6) {
+        check(&YEAR, year)?;
+    }
+
+    let dom_text = fields[3];
+    let dow_text = fields[5];
+    let dom = DomRule::parse(dom_text)
+        .map_err(|e| format!("{} field `{}`: {}", DAY_OF_MONTH.name, dom_text, e))?;
+    let dow = DowRule::parse(dow_text, dialect)
+        .map_err(|e| format!("day-of-week field `{}`: {}", dow_text, e))?;
+
+    // Unix 里日和周都有限制时是“或”，cron 是“且”，也交给过滤处理
+    let either = dialect == Dialect::Unix && is_restricted(dom_text) && is_restricted(dow_text);
+    let filtered = dom.is_special() || dow.is_special() || either;
+    let (dom_cron, dow_cron) = if filtered {
+        ("*".to_string(), "*".to_string())
+    } else if is_restricted(dow_text) {
+        (dom_text.replace('?', "*"), dow.names())
+    } else {
+        (dom_text.replace('?', "*"), "*".to_string())
+    };
+    let days = filtered.then(|| DayFilter {
+        dom: is_restricted(dom_text).then_some(dom),
+        dow: is_restricted(dow_text).then_some(dow),
+        either,
+    });
+    let mut source = format!(
+        "{} {} {} {} {} {}",
+        fields[0], fields[1], fields[2], dom_cron, fields[4], dow_cron
+    );
+    if let Some(year) = fields.get(6) {
+        source.push(' ');
+        source.push_str(year);
+    }
+    let schedule = Schedule::from_str(&source).map_err(|e| e.to_string())?;
+    Ok(Expression::Cron(Box::new(CronExpr { schedule, days })))
+}
+
+impl CronExpr {
+    /// `after` 之后（不含）的下一次触发，都是本地时间
+    pub fn after(&self, after: NaiveDateTime) -> Option<NaiveDateTime> {
+        let limit = after + Duration::days(366 * 10);
+        let mut cursor = after;
+        loop {
+            let candidate = self
+                .schedule
+                .after(&Utc.from_utc_datetime(&cursor))
+                .next()?
+                .naive_utc();
+            let days = match &self.days {
+                Some(days) => days,
+                None => return Some(candidate),
+            };
+            if days.matches(candidate.date()) {
+                return Some(candidate);
+            }
+            if candidate > limit {
+                // 比如只写了 2 月的 30W，永远不会触发
+                return None;
+            }
+            // 这一天不满足，直接跳到第二天
+            cursor = candidate.date().succ_opt()?.and_hms_opt(0, 0, 0)? - Duration::seconds(1);
+        }
+    }
+}
+
+/// cron 不支持的日/周写法，按日期过滤
+struct DayFilter {
+    dom: Option<DomRule>,
+    dow: Option<DowRule>,
+    either: bool,
+}
+
+impl DayFilter {
+    fn matches(&self, date: NaiveDate) -> bool {
+        let dom = self.dom.as_ref().map(|rule| rule.matches(date));
+        let dow = self.dow.as_ref().map(|rule| rule.matches(date));
+        match (dom, dow) {
+            (Some(dom), Some(dow)) if self.either => dom || dow,
+            (Some(dom), Some(dow)) => dom && dow,
+            (Some(matched), None) | (None, Some(matched)) => matched,
+            (None, None) => true,
+        }
+    }
+}
+
+#[derive(Default)]
+struct DomRule {
+    days: BTreeSet<u32>,
+    /// `L` 是 0，`L-3` 是 3
+    last: Vec<u32>,
+    /// `LW`，当月最后一个工作日
+    last_weekday: bool,
+    /// `15W`，离 15 号最近的工作日，不跨月
+    nearest: Vec<u32>,
+}
+
+impl DomRule {
+    fn parse(text: &str) -> Result<Self, String> {
+        let mut rule = DomRule::default();
+        for item in text.split(',') {
+            if item.eq_ignore_ascii_case("L") {
+                rule.last.push(0);
+            } else if item.eq_ignore_ascii_case("LW") {
+                rule.last_weekday = true;
+            } else if let Some(offset) = item.strip_prefix("L-").or(item.strip_prefix("l-")) {
+                match offset.parse::<u32>() {
+                    Ok(offset) if offset <= 30 => rule.last.push(offset),
+                    _ => return Err(format!("invalid offset `{item}`")),
+                }
+            } else if let Some(day) = item.strip_suffix('W').or(item.strip_suffix('w')) {
+                rule.nearest.push(DAY_OF_MONTH.value(day)?);
+            } else {
+                rule.days.extend(DAY_OF_MONTH.values(item)?);
+            }
+        }
+        Ok(rule)
+    }
+
+    fn is_special(&self) -> bool {
+        !self.last.is_empty() || self.last_weekday || !self.nearest.is_empty()
+    }
+
+    fn matches(&self, date: NaiveDate) -> bool {
+        let day = date.day();
+        let last_day = days_in_month(date);
+        self.days.contains(&day)
+            || self
+                .last
+                .iter()
+                .any(|offset| last_day.checked_sub(*offset) == Some(day))
+            || (self.last_weekday && nearest_weekday(date, last_day, last_day) == Some(day))
+            || self
+                .nearest
+                .iter()
+                .any(|target| nearest_weekday(date, *target, last_day) == Some(day))
+    }
+}
+
+/// 星期都换成周日为 0 的数字
+#[derive(Default)]
+struct DowRule {
+    days: BTreeSet<u32>,
+    /// `5L`，当月最后一个星期五
+    last: Vec<u32>,
+    /// `6#3`，当月第三个星期五
+    nth: Vec<(u32, u32)>,
+}
+
+impl DowRule {
+    fn parse(text: &str, dialect: Dialect) -> Result<Self, String> {
+        let field = Field::day_of_week(dialect);
+        let normalize = |value: u32| (value - field.name_base) % 7;
+        let mut rule = DowRule::default();
+        for item in text.split(',') {
+            if item.eq_ignore_ascii_case("L") {
+                // Quartz 里单独的 L 是星期六
+                rule.days.insert(6);
+            } else if let Some((weekday, nth)) = item.split_once('#') {
+                match nth.parse::<u32>() {
+                    Ok(nth) if (1..=5).contains(&nth) => {
+                        rule.nth.push((normalize(field.value(weekday)?), nth))
+                    }
+                    _ => return Err(format!("invalid occurrence `{nth}`, expected 1-5")),
+                }
+            } else if let Some(weekday) = item
+                .strip_suffix('L')
+                .or(item.strip_suffix('l'))
+                .filter(|weekday| !weekday.is_empty())
+            {
+                rule.last.push(normalize(field.value(weekday)?));
+            } else {
+                rule.days
+                    .extend(field.values(item)?.into_iter().map(normalize));
+            }
+        }
+        Ok(rule)
+    }
+
+    fn is_special(&self) -> bool {
+        !self.last.is_empty() || !self.nth.is_empty()
+    }
+
+    /// 交给 cron 的写法
+    fn names(&self) -> String {
+        self.days
+            .iter()
+            .map(|day| WEEKDAY_NAMES[*day as usize][0])
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    fn matches(&self, date: NaiveDate) -> bool {
+        let weekday = date.weekday().num_days_from_sunday();
+        let day = date.day();
+        self.days.contains(&weekday)
+            || (self.last.contains(&weekday) && day + 7 > days_in_month(date))
+            || self
+                .nth
+                .iter()
+                .any(|(w, nth)| *w == weekday && (day - 1) / 7 + 1 == *nth)
+    }
+}
+
+fn days_in_month(date: NaiveDate) -> u32 {
+    let (year, month) = match date.month() {
+        12 => (date.year() + 1, 1),
+        month => (date.year(), month + 1),
+    };
+    NaiveDate::from_ymd_opt(year, month, 1)
+        .and_then(|next| next.pred_opt())
+        .map_or(31, |last| last.day())
+}
+
+/// 离 `target` 号最近的工作日，不跨月；`target` 超过当月天数时没有
+fn nearest_weekday(date: NaiveDate, target: u32, last_day: u32) -> Option<u32> {
+    if target > last_day {
+        return None;
+    }
+    let weekday = date.with_day(target)?.weekday().num_days_from_sunday();
+    Some(match weekday {
+        6 if target == 1 => 3,
+        6 => target - 1,
+        0 if target == last_day => target - 2,
+        0 => target + 1,
+        _ => target,
+    })
+}
+
+/// 英文描述，比如 `every 15 seconds`、`at 02:00 on Mondays`
+pub fn describe(expression: &str) -> Result<String, String> {
+    let expression = expression.trim();
+    parse(expression)?;
+    let (dialect, fields) = match split(expression)? {
+        Some(split) => split,
+        None => return Ok("at startup".to_string()),
+    };
+    let [sec, min, hour, dom, month, dow] = [0, 1, 2, 3, 4, 5].map(|i| fields[i].as_str());
+
+    let mut parts = vec![describe_time(
+        Spec::parse(&SECOND, sec),
+        Spec::parse(&MINUTE, min),
+        Spec::parse(&HOUR, hour),
+    )];
+    let dom = is_restricted(dom).then(|| describe_dom(dom));
+    let dow = is_restricted(dow).then(|| describe_dow(dow, dialect));
+    match (dom, dow) {
+        (Some(dom), Some(dow)) if dialect == Dialect::Unix => parts.push(format!("{dom} or {dow}")),
+        (Some(dom), Some(dow)) => parts.push(format!("{dom} and {dow}")),
+        (Some(days), None) | (None, Some(days)) => parts.push(days),
+        (None, None) => {}
+    }
+    let month_name = |month: u32| MONTH_LONG_NAMES[month as usize - 1].to_string();
+    match Spec::parse(&MONTH, month) {
+        Spec::Any => {}
+        Spec::Value(month) => parts.push(format!("in {}", month_name(month))),
+        Spec::List(months) => parts.push(format!(
+            "in {}",
+            join(months.into_iter().map(month_name).collect())
+        )),
+        Spec::Range(start, end) => parts.push(format!(
+            "from {} through {}",
+            month_name(start),
+            month_name(end)
+        )),
+        spec => parts.push(spec.phrase("month", "months")),
+    }
+    match fields.get(6).map(|year| (year, Spec::parse(&YEAR, year))) {
+        None | Some((_, Spec::Any)) => {}
+        Some((_, Spec::Value(year))) => parts.push(format!("in {year}")),
+        Some((year, _)) => parts.push(format!("in years {year}")),
+    }
+    Ok(parts.join(" "))
+}
+
+const MONTH_LONG_NAMES: &[&str] = &[
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+const WEEKDAY_LONG_NAMES: &[&str] = &[
+    "Sunday",
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+];
+
+/// 描述用的字段写法，解析前已经校验过
+enum Spec {
+    Any,
+    /// `*/n`
+    Every(u32),
+    Value(u32),
+    Range(u32, u32),
+    /// `a/n` 或 `a-b/n`
+    Step(u32, u32, u32),
+    List(Vec<u32>),
+}
+
+impl Spec {
+    fn parse(field: &Field, text: &str) -> Self {
+        if !is_restricted(text) {
+            return Spec::Any;
+        }
+        let value = |text: &str| field.value(text).unwrap_or(field.min);
+        if text.contains(',') {
+            return Spec::List(field.values(text).unwrap_or_default().into_iter().collect());
+        }
+        let range = |text: &str| match text.split_once('-') {
+            Some((start, end)) => (value(start), value(end)),
+            None => (value(text), field.max),
+        };
+        match text.split_once('/') {
+            Some(("*", step)) => Spec::Every(step.parse().unwrap_or(1)),
+            Some((start, step)) => match (range(start), step.parse().unwrap_or(1)) {
+                // `0/15` 和 `*/15` 是一样的
+                ((start, end), step) if start == field.min && end == field.max => Spec::Every(step),
+                ((start, end), step) => Spec::Step(start, end, step),
+            },
+            None => match text.split_once('-') {
+                Some(_) => {
+                    let (start, end) = range(text);
+                    Spec::Range(start, end)
+                }
+                None => Spec::Value(value(text)),
+            },
+        }
+    }
+
+    fn phrase(&self, unit: &str, plural: &str) -> String {
+        match self {
+            Spec::Any => format!("every {unit}"),
+            Spec::Every(step) => format!("every {step} {plural}"),
+            Spec::Value(value) => format!("at {unit} {value}"),
+            Spec::Range(start, end) => format!("every {unit} from {start} through {end}"),
+            Spec::Step(start, end, step) => {
+                format!("every {step} {plural} from {start} through {end}")
+            }
+            Spec::List(values) => format!(
+                "at {plural} {}",
+                join(values.iter().map(u32::to_string).collect())
+            ),
+        }
+    }
+}
+
+fn describe_time(sec: Spec, min: Spec, hour: Spec) -> String {
+    let clock = |hour: u32, min: u32, sec: u32| match sec {
+        0 => format!("{hour:02}:{min:02}"),
+        sec => format!("{hour:02}:{min:02}:{sec:02}"),
+    };
+    match (&sec, &min, &hour) {
+        (Spec::Value(s), Spec::Value(m), Spec::Value(h)) => format!("at {}", clock(*h, *m, *s)),
+        (Spec::Value(s), Spec::Value(m), Spec::List(hours)) => format!(
+            "at {}",
+            join(hours.iter().map(|h| clock(*h, *m, *s)).collect())
+        ),
+        (Spec::Value(0), Spec::Value(0), Spec::Any) => "every hour".to_string(),
+        (Spec::Value(s), Spec::Value(m), Spec::Any) => match s {
+            0 => format!("every hour at minute {m}"),
+            s => format!("every hour at {m:02}:{s:02}"),
+        },
+        (_, Spec::Value(m), Spec::Value(h)) => {
+            format!(
+                "{} during {}",
+                sec.phrase("second", "seconds"),
+                clock(*h, *m, 0)
+            )
+        }
+        _ => {
+            let mut parts = Vec::new();
+            match sec {
+                Spec::Value(0) => {}
+                ref sec => parts.push(sec.phrase("second", "seconds")),
+            }
+            match min {
+                Spec::Any if !matches!(sec, Spec::Value(_)) => {}
+                ref min => parts.push(min.phrase("minute", "minutes")),
+            }
+            match hour {
+                Spec::Any => {}
+                Spec::Value(h) => parts.push(format!("between {h:02}:00 and {h:02}:59")),
+                Spec::Range(start, end) => {
+                    parts.push(format!("between {start:02}:00 and {end:02}:59"))
+                }
+                ref hour => parts.push(hour.phrase("hour", "hours")),
+            }
+            parts.join(", ")
+        }
+    }
+}
+
+fn describe_dom(text: &str) -> String {
+    let dom = match DomRule::parse(text) {
+        Ok(dom) if dom.is_special() => dom,
+        _ => {
+            return match Spec::parse(&DAY_OF_MONTH, text) {
+                Spec::Value(day) => format!("on day {day} of the month"),
+                Spec::List(days) => format!(
+                    "on days {} of the month",
+                    join(days.iter().map(u32::to_string).collect())
+                ),
+                Spec::Range(start, end) => format!("on days {start} through {end} of the month"),
+                spec => spec.phrase("day", "days"),
+            }
+        }
+    };
+    let mut parts = Vec::new();
+    if !dom.days.is_empty() {
+        parts.push(format!(
+            "day {}",
+            join(dom.days.iter().map(u32::to_string).collect())
+        ));
+    }
+    for offset in &dom.last {
+        parts.push(match offset {
+            0 => "the last day".to_string(),
+            1 => "1 day before the last day".to_string(),
+            offset => format!("{offset} days before the last day"),
+        });
+    }
+    if dom.last_weekday {
+        parts.push("the last weekday".to_string());
+    }
+    for day in &dom.nearest {
+        parts.push(format!("the weekday nearest day {day}"));
+    }
+    format!("on {} of the month", join(parts))
+}
+
+fn describe_dow(text: &str, dialect: Dialect) -> String {
+    let dow = match DowRule::parse(text, dialect) {
+        Ok(dow) => dow,
+        Err(_) => return format!("on weekdays {text}"),
+    };
+    let name = |day: u32| WEEKDAY_LONG_NAMES[day as usize];
+    let mut parts = Vec::new();
+    // 周一到周日排序，方便把连续的几天写成 through
+    let mut days: Vec<u32> = dow.days.iter().copied().collect();
+    days.sort_by_key(|day| (day + 6) % 7);
+    // 连续的几天可能跨过周日，比如周日到周四，从唯一的断开处之后开始算
+    let gaps: Vec<usize> = (0..days.len())
+        .filter(|&i| (days[i] + 1) % 7 != days[(i + 1) % days.len()])
+        .collect();
+    let start = match gaps[..] {
+        [] => Some(0),
+        [gap] => Some((gap + 1) % days.len()),
+        _ => None,
+    };
+    if let Some(start) = start.filter(|_| days.len() >= 3) {
+        parts.push(format!(
+            "{} through {}",
+            name(days[start]),
+            name(days[(start + days.len() - 1) % days.len()])
+        ));
+    } else if !days.is_empty() {
+        parts.push(join(
+            days.iter().map(|day| format!("{}s", name(*day))).collect(),
+        ));
+    }
+    const ORDINALS: [&str; 5] = ["first", "second", "third", "fourth", "fifth"];
+    for (day, nth) in &dow.nth {
+        parts.push(format!(
+            "the {} {} of the month",
+            ORDINALS[*nth as usize - 1],
+            name(*day)
+        ));
+    }
+    for day in &dow.last {
+        parts.push(format!("the last {} of the month", name(*day)));
+    }
+    format!("on {}", join(parts))
+}
+
+/// `a`、`a and b`、`a, b and c`
+fn join(mut items: Vec<String>) -> String {
+    match items.len() {
+        0 => String::new(),
+        1 => items.remove(0),
+        _ => {
+            let last = items.pop().unwrap_or_default();
+            format!("{} and {}", items.join(", "), last)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `from` 之后的 `count` 次触发
+    fn runs(expression: &str, from: &str, count: usize) -> Vec<String> {
+        let expr = match parse(expression) {
+            Ok(Expression::Cron(expr)) => expr,
+            Ok(Expression::Reboot) => panic!("`{expression}` is @reboot"),
+            Err(e) => panic!("{e}"),
+        };
+        let mut cursor = NaiveDateTime::parse_from_str(from, "%Y-%m-%d %H:%M").unwrap();
+        (0..count)
+            .map(|_| {
+                cursor = expr.after(cursor).unwrap();
+                cursor.format("%Y-%m-%d %H:%M").to_string()
+            })
+            .collect()
+    }
+
+    fn error(expression: &str) -> String {
+        parse(expression).err().expect("expected an error")
+    }
+
+    #[test]
+    fn unix_day_of_month_or_day_of_week() {
+        // 13 号或者星期五
+        assert_eq!(
+            runs("0 0 13 * 5", "2026-02-01 00:00", 5),
+            [
+                "2026-02-06 00:00",
+                "2026-02-13 00:00",
+                "2026-02-20 00:00",
+                "2026-02-27 00:00",
+                "2026-03-06 00:00",
+            ]
+        );
+        // 只限制一个时按那一个
+        assert_eq!(
+            runs("0 0 13 * *", "2026-02-01 00:00", 2),
+            ["2026-02-13 00:00", "2026-03-13 00:00"]
+        );
+    }
+
+    #[test]
+    fn quartz_day_of_month_and_day_of_week() {
+        assert_eq!(
+            runs("0 0 0 13 * FRI", "2026-01-01 00:00", 2),
+            ["2026-02-13 00:00", "2026-03-13 00:00"]
+        );
+    }
+
+    #[test]
+    fn last_day_of_month() {
+        assert_eq!(
+            runs("0 0 0 L * ?", "2026-01-15 00:00", 3),
+            ["2026-01-31 00:00", "2026-02-28 00:00", "2026-03-31 00:00"]
+        );
+        assert_eq!(
+            runs("0 0 0 L-2 * ?", "2026-01-15 00:00", 3),
+            ["2026-01-29 00:00", "2026-02-26 00:00", "2026-03-29 00:00"]
+        );
+        assert_eq!(
+            runs("0 0 0 LW * ?", "2026-01-15 00:00", 3),
+            ["2026-01-30 00:00", "2026-02-27 00:00", "2026-03-31 00:00"]
+        );
+    }
+
+    #[test]
+    fn nearest_weekday() {
+        // 2 月 15 号和 3 月 15 号是星期日
+        assert_eq!(
+            runs("0 0 0 15W * ?", "2026-02-01 00:00", 2),
+            ["2026-02-16 00:00", "2026-03-16 00:00"]
+        );
+        // 8 月 15 号是星期六
+        assert_eq!(
+            runs("0 0 0 15W 8 ?", "2026-08-01 00:00", 1),
+            ["2026-08-14 00:00"]
+        );
+        // 8 月 1 号是星期六，不会跨到 7 月
+        assert_eq!(
+            runs("0 0 0 1W 8 ?", "2026-07-01 00:00", 1),
+            ["2026-08-03 00:00"]
+        );
+    }
+
+    #[test]
+    fn last_weekday_of_month() {
+        let last_fridays = ["2026-01-30 00:00", "2026-02-27 00:00", "2026-03-27 00:00"];
+        assert_eq!(runs("0 0 0 ? * 6L", "2026-01-01 00:00", 3), last_fridays);
+        assert_eq!(runs("0 0 0 ? * FRIL", "2026-01-01 00:00", 3), last_fridays);
+        assert_eq!(runs("0 0 * * 5L", "2026-01-01 00:00", 3), last_fridays);
+    }
+
+    #[test]
+    fn nth_weekday_of_month() {
+        let third_fridays = ["2026-01-16 00:00", "2026-02-20 00:00", "2026-03-20 00:00"];
+        assert_eq!(runs("0 0 0 ? * 6#3", "2026-01-01 00:00", 3), third_fridays);
+        assert_eq!(
+            runs("0 0 0 ? * FRI#3", "2026-01-01 00:00", 3),
+            third_fridays
+        );
+        assert_eq!(runs("0 0 * * 5#3", "2026-01-01 00:00", 3), third_fridays);
+    }
+
+    #[test]
+    fn sunday_numbering() {
+        let sundays = ["2026-01-04 00:00", "2026-01-11 00:00"];
+        assert_eq!(runs("0 0 * * 0", "2026-01-01 00:00", 2), sundays);
+        assert_eq!(runs("0 0 * * 7", "2026-01-01 00:00", 2), sundays);
+        assert_eq!(runs("0 0 0 ? * 1", "2026-01-01 00:00", 2), sundays);
+        assert_eq!(runs("0 0 0 ? * SUN", "2026-01-01 00:00", 2), sundays);
+
+        let mondays = ["2026-01-05 00:00", "2026-01-12 00:00"];
+        assert_eq!(runs("0 0 * * 1", "2026-01-01 00:00", 2), mondays);
+        assert_eq!(runs("0 0 0 ? * 2", "2026-01-01 00:00", 2), mondays);
+    }
+
+    #[test]
+    fn macros() {
+        let from = "2026-01-01 00:00";
+        assert_eq!(runs("@hourly", from, 1), ["2026-01-01 01:00"]);
+        assert_eq!(runs("@daily", from, 1), ["2026-01-02 00:00"]);
+        assert_eq!(runs("@weekly", from, 1), ["2026-01-04 00:00"]);
+        assert_eq!(runs("@monthly", from, 1), ["2026-02-01 00:00"]);
+        assert_eq!(runs("@yearly", from, 1), ["2027-01-01 00:00"]);
+        assert!(matches!(parse("@reboot"), Ok(Expression::Reboot)));
+        assert_eq!(
+            error("@often"),
+            "invalid cron expression `@often`: unknown macro `@often`"
+        );
+    }
+
+    #[test]
+    fn errors_name_the_field() {
+        assert_eq!(
+            error("0 25 * * *"),
+            "invalid cron expression `0 25 * * *`: hour field `25`: value 25 out of range 0-23"
+        );
+        assert!(error("0 0 * 13 *").contains("month field `13`"));
+        assert!(error("60 * * * * *").contains("second field `60`"));
+        assert!(error("0 0 0 L-40 * ?").contains("day-of-month field `L-40`"));
+        assert!(error("0 0 * * 5#6").contains("day-of-week field `5#6`"));
+        assert!(error("0 0 0 * * * 1900").contains("year field `1900`"));
+        assert!(error("0 0 *").contains("expected 5, 6 or 7 fields, got 3"));
+        assert!(error("0 0 9 * * Mondays").contains("day-of-week field `Mondays`"));
+        assert!(error("0 0 0 * * * 2101").contains("year field `2101`"));
+    }
+
+    #[test]
+    fn names_accepted_by_cron() {
+        let mondays = ["2026-01-05 09:00", "2026-01-12 09:00"];
+        assert_eq!(runs("0 0 9 * * Monday", "2026-01-01 00:00", 2), mondays);
+        assert_eq!(runs("0 9 * * monday", "2026-01-01 00:00", 2), mondays);
+        assert_eq!(
+            runs("0 0 9 ? * Tues-Thurs", "2026-01-05 12:00", 3),
+            ["2026-01-06 09:00", "2026-01-07 09:00", "2026-01-08 09:00"]
+        );
+        assert_eq!(
+            runs("0 0 0 1 JANUARY *", "2026-01-01 00:00", 1),
+            ["2027-01-01 00:00"]
+        );
+        assert!(parse("0 0 0 1 1 * 2100").is_ok());
+    }
+
+    #[test]
+    fn describe_weekday_ranges() {
+        assert_eq!(
+            describe("0 0 0 * * 1-5").unwrap(),
+            "at 00:00 on Sunday through Thursday"
+        );
+        assert_eq!(
+            describe("0 0 * * 1-5").unwrap(),
+            "at 00:00 on Monday through Friday"
+        );
+        assert_eq!(
+            describe("0 0 * * 5-7,1").unwrap(),
+            "at 00:00 on Friday through Monday"
+        );
+    }
+
+    #[test]
+    fn describe_steps() {
+        assert_eq!(describe("0/15 * * * * *").unwrap(), "every 15 seconds");
+        assert_eq!(
+            describe("5/15 * * * * *").unwrap(),
+            "every 15 seconds from 5 through 59"
+        );
+    }
+}
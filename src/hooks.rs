@@ -0,0 +1,84 @@
+use crate::log::log;
+use mlua::prelude::*;
+use std::cell::RefCell;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hook {
+    BeforeRun,
+    AfterRun,
+    OnSuccess,
+    OnError,
+    OnShutdown,
+}
+
+impl Hook {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Hook::BeforeRun => "before_run",
+            Hook::AfterRun => "after_run",
+            Hook::OnSuccess => "on_success",
+            Hook::OnError => "on_error",
+            Hook::OnShutdown => "on_shutdown",
+        }
+    }
+}
+
+/// `sched:on_error(fn)` 等注册的全局回调，同一种可以注册多个，按注册顺序调用
+#[derive(Default)]
+pub struct Hooks {
+    before_run: RefCell<Vec<LuaFunction<'static>>>,
+    after_run: RefCell<Vec<LuaFunction<'static>>>,
+    on_success: RefCell<Vec<LuaFunction<'static>>>,
+    on_error: RefCell<Vec<LuaFunction<'static>>>,
+    on_shutdown: RefCell<Vec<LuaFunction<'static>>>,
+}
+
+impl Hooks {
+    fn list(&self, hook: Hook) -> &RefCell<Vec<LuaFunction<'static>>> {
+        match hook {
+            Hook::BeforeRun => &self.before_run,
+            Hook::AfterRun => &self.after_run,
+            Hook::OnSuccess => &self.on_success,
+            Hook::OnError => &self.on_error,
+            Hook::OnShutdown => &self.on_shutdown,
+        }
+    }
+
+    pub fn add(&self, hook: Hook, func: LuaFunction<'static>) {
+        self.list(hook).borrow_mut().push(func);
+    }
+
+    pub fn is_empty(&self, hook: Hook) -> bool {
+        self.list(hook).borrow().is_empty()
+    }
+
+    /// 回调出错只记录日志，不影响任务本身
+    pub async fn call(&self, hook: Hook, job: &str, ctx: LuaTable<'static>) {
+        // 回调里可能会再注册回调，不能在 await 期间借用
+        let funcs = self.list(hook).borrow().clone();
+        for func in funcs {
+            if let Err(e) = func.call_async::<_, ()>(ctx.clone()).await {
+                log!(
+                    "ERROR",
+                    "{} hook for job `{}` failed: {}",
+                    hook.as_str(),
+                    job,
+                    e
+                );
+            }
+        }
+    }
+
+    /// 退出前调用 `on_shutdown` 回调，全部成功时返回 `true`
+    pub async fn shutdown(&self, ctx: LuaTable<'static>) -> bool {
+        let funcs = self.on_shutdown.borrow().clone();
+        let mut ok = true;
+        for func in funcs {
+            if let Err(e) = func.call_async::<_, ()>(ctx.clone()).await {
+                log!("ERROR", "on_shutdown hook failed: {}", e);
+                ok = false;
+            }
+        }
+        ok
+    }
+}
@@ -0,0 +1,451 @@
+use crate::options::{JobOptions, Overlap};
+use chrono::{DateTime, Utc};
+use mlua::prelude::*;
+use std::{
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+    rc::Rc,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use tokio::{
+    sync::{Notify, Semaphore},
+    task::{AbortHandle, JoinHandle},
+    time::sleep,
+};
+
+static NEXT_JOB_ID: AtomicU64 = AtomicU64::new(1);
+
+/// 每个任务保留的执行记录条数
+const HISTORY_SIZE: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobStatus {
+    Scheduled,
+    Paused,
+    Cancelled,
+    Finished,
+}
+
+impl JobStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Scheduled => "scheduled",
+            JobStatus::Paused => "paused",
+            JobStatus::Cancelled => "cancelled",
+            JobStatus::Finished => "finished",
+        }
+    }
+}
+
+/// 任务为什么结束
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FinishReason {
+    /// 没有下一次触发时间了，比如一次性任务已经执行过
+    Completed,
+    /// 下一次触发晚于 `end_at`
+    EndAt,
+    /// 成功次数达到 `max_runs`
+    MaxRuns,
+}
+
+impl FinishReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FinishReason::Completed => "completed",
+            FinishReason::EndAt => "end_at",
+            FinishReason::MaxRuns => "max_runs",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunStatus {
+    Running,
+    Success,
+    Failed,
+    /// 超过 `timeout` 被中止
+    TimedOut,
+    /// 因为 overlap 策略没有执行
+    Skipped,
+    /// 被 `replace` 中止
+    Aborted,
+}
+
+impl RunStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RunStatus::Running => "running",
+            RunStatus::Success => "success",
+            RunStatus::Failed => "failed",
+            RunStatus::TimedOut => "timeout",
+            RunStatus::Skipped => "skipped",
+            RunStatus::Aborted => "aborted",
+        }
+    }
+}
+
+/// 一次触发的执行结果
+#[derive(Debug, Clone)]
+pub struct RunRecord {
+    pub run_id: u64,
+    pub scheduled_at: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub status: RunStatus,
+    /// 算上重试一共调用了几次
+    pub attempts: u32,
+    pub overlap: Overlap,
+    pub error: Option<String>,
+    /// 因为哪个并发限制等待或者被跳过
+    pub limit: Option<String>,
+    /// 等并发名额花的时间
+    pub waited: Option<Duration>,
+    /// 任务函数成功时的返回值
+    pub result: Option<serde_json::Value>,
+}
+
+impl RunRecord {
+    pub fn to_table<'lua>(&self, lua: &'lua Lua) -> LuaResult<LuaTable<'lua>> {
+        let table = lua.create_table()?;
+        table.set("run_id", self.run_id)?;
+        table.set("scheduled_at", self.scheduled_at.timestamp())?;
+        table.set("started_at", self.started_at.map(|t| t.timestamp()))?;
+        table.set("finished_at", self.finished_at.map(|t| t.timestamp()))?;
+        if let (Some(started_at), Some(finished_at)) = (self.started_at, self.finished_at) {
+            let duration = (finished_at - started_at).num_milliseconds() as f64 / 1000.0;
+            table.set("duration", duration)?;
+        }
+        table.set("status", self.status.as_str())?;
+        table.set("attempts", self.attempts)?;
+        table.set("overlap", self.overlap.as_str())?;
+        table.set("error", self.error.clone())?;
+        table.set("limit", self.limit.clone())?;
+        table.set("waited", self.waited.map(|waited| waited.as_secs_f64()))?;
+        if let Some(result) = &self.result {
+            table.set("result", lua.to_value(result)?)?;
+        }
+        Ok(table)
+    }
+}
+
+pub type Listener = Box<dyn Fn(&RunRecord)>;
+
+/// 任务循环和 Lua 侧 `Job` 句柄共享的状态
+pub struct JobState {
+    pub id: u64,
+    pub name: String,
+    pub description: Option<String>,
+    pub priority: i64,
+    /// 上游任务名
+    pub after: Vec<String>,
+    status: Cell<JobStatus>,
+    finish_reason: Cell<Option<FinishReason>>,
+    running: Cell<usize>,
+    queued: Cell<usize>,
+    runs: Cell<u64>,
+    /// 上一次成功执行的触发时间
+    last_success: Cell<Option<DateTime<Utc>>>,
+    next_run_id: Cell<u64>,
+    next_run: Cell<Option<DateTime<Utc>>>,
+    history: RefCell<VecDeque<RunRecord>>,
+    active: RefCell<Vec<JoinHandle<()>>>,
+    /// `join_active` 会取走 `active`，中止和判断是否结束用这里的
+    aborts: RefCell<Vec<AbortHandle>>,
+    /// `queue` 模式下保证同一时间只有一次在跑
+    slot: Semaphore,
+    notify: Notify,
+    /// 每条执行记录完成时调用，下游任务靠它触发，按注册它的任务 id 区分
+    listeners: RefCell<Vec<(u64, Listener)>>,
+}
+
+impl JobState {
+    pub fn new(options: &JobOptions) -> Self {
+        let id = NEXT_JOB_ID.fetch_add(1, Ordering::Relaxed);
+        JobState {
+            id,
+            name: options.name.clone().unwrap_or_else(|| format!("job-{id}")),
+            description: options.description.clone(),
+            priority: options.priority,
+            after: options.after.clone(),
+            status: Cell::new(JobStatus::Scheduled),
+            finish_reason: Cell::new(None),
+            running: Cell::new(0),
+            queued: Cell::new(0),
+            runs: Cell::new(0),
+            last_success: Cell::new(None),
+            next_run_id: Cell::new(1),
+            next_run: Cell::new(None),
+            history: RefCell::new(VecDeque::with_capacity(HISTORY_SIZE)),
+            active: RefCell::new(Vec::new()),
+            aborts: RefCell::new(Vec::new()),
+            slot: Semaphore::new(1),
+            notify: Notify::new(),
+            listeners: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn status(&self) -> JobStatus {
+        self.status.get()
+    }
+
+    /// 已经取消或结束，不会再触发
+    pub fn is_stopped(&self) -> bool {
+        matches!(
+            self.status.get(),
+            JobStatus::Cancelled | JobStatus::Finished
+        )
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.status.get() == JobStatus::Cancelled
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.status.get() == JobStatus::Paused
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.get() > 0
+    }
+
+    /// 成功执行的次数
+    pub fn runs(&self) -> u64 {
+        self.runs.get()
+    }
+
+    pub fn last_success(&self) -> Option<DateTime<Utc>> {
+        self.last_success.get()
+    }
+
+    /// 从保存的状态里恢复
+    pub fn restore(&self, last_success: Option<DateTime<Utc>>) {
+        self.last_success.set(last_success);
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queued.get()
+    }
+
+    pub fn set_queued(&self, queued: usize) {
+        self.queued.set(queued);
+    }
+
+    pub fn slot(&self) -> &Semaphore {
+        &self.slot
+    }
+
+    pub fn last_run(&self) -> Option<RunRecord> {
+        self.history.borrow().back().cloned()
+    }
+
+    /// 最近的执行记录，从旧到新
+    pub fn history(&self) -> Vec<RunRecord> {
+        self.history.borrow().iter().cloned().collect()
+    }
+
+    pub fn new_record(&self, scheduled_at: DateTime<Utc>, overlap: Overlap) -> RunRecord {
+        let run_id = self.next_run_id.get();
+        self.next_run_id.set(run_id + 1);
+        RunRecord {
+            run_id,
+            scheduled_at,
+            started_at: None,
+            finished_at: None,
+            status: RunStatus::Running,
+            attempts: 0,
+            overlap,
+            error: None,
+            limit: None,
+            waited: None,
+            result: None,
+        }
+    }
+
+    pub fn run_started(&self) {
+        self.running.set(self.running.get() + 1);
+    }
+
+    pub fn run_finished(&self, record: RunRecord) {
+        if record.started_at.is_some() {
+            self.running.set(self.running.get() - 1);
+        }
+        if record.status == RunStatus::Success {
+            self.runs.set(self.runs.get() + 1);
+            self.last_success.set(Some(record.scheduled_at));
+        }
+        {
+            let mut history = self.history.borrow_mut();
+            if history.len() == HISTORY_SIZE {
+                history.pop_front();
+            }
+            history.push_back(record.clone());
+        }
+        for (_, listener) in self.listeners.borrow().iter() {
+            listener(&record);
+        }
+    }
+
+    pub fn on_finished(&self, owner: u64, listener: Listener) {
+        self.listeners.borrow_mut().push((owner, listener));
+    }
+
+    /// 去掉任务 `owner` 注册的监听
+    pub fn remove_listeners(&self, owner: u64) {
+        self.listeners
+            .borrow_mut()
+            .retain(|(listener_owner, _)| *listener_owner != owner);
+    }
+
+    pub fn track(&self, handle: JoinHandle<()>) {
+        let mut aborts = self.aborts.borrow_mut();
+        aborts.retain(|handle| !handle.is_finished());
+        aborts.push(handle.abort_handle());
+        let mut active = self.active.borrow_mut();
+        active.retain(|handle| !handle.is_finished());
+        active.push(handle);
+    }
+
+    /// 中止所有正在执行的
+    pub fn abort_active(&self) {
+        for handle in self.aborts.borrow().iter() {
+            handle.abort();
+        }
+    }
+
+    /// 派发出去的执行都已经结束
+    pub fn is_idle(&self) -> bool {
+        self.aborts
+            .borrow()
+            .iter()
+            .all(|handle| handle.is_finished())
+    }
+
+    /// 等待所有已经派发的执行（包括排队中的）结束
+    pub async fn join_active(&self) {
+        loop {
+            let handles = std::mem::take(&mut *self.active.borrow_mut());
+            if handles.is_empty() {
+                break;
+            }
+            for handle in handles {
+                let _ = handle.await;
+            }
+        }
+    }
+
+    pub fn next_run(&self) -> Option<DateTime<Utc>> {
+        self.next_run.get()
+    }
+
+    pub fn set_next_run(&self, next_run: Option<DateTime<Utc>>) {
+        self.next_run.set(next_run);
+    }
+
+    pub fn cancel(&self) {
+        self.stop(JobStatus::Cancelled);
+    }
+
+    /// 任务正常结束，已经取消的任务不受影响
+    pub fn finish(&self, reason: FinishReason) {
+        if !self.is_stopped() {
+            self.finish_reason.set(Some(reason));
+        }
+        self.stop(JobStatus::Finished);
+    }
+
+    pub fn finish_reason(&self) -> Option<FinishReason> {
+        self.finish_reason.get()
+    }
+
+    fn stop(&self, status: JobStatus) {
+        if !self.is_stopped() {
+            self.status.set(status);
+        }
+        self.next_run.set(None);
+        self.notify.notify_waiters();
+    }
+
+    pub fn pause(&self) {
+        if self.status.get() == JobStatus::Scheduled {
+            self.status.set(JobStatus::Paused);
+        }
+    }
+
+    pub fn resume(&self) {
+        if self.status.get() == JobStatus::Paused {
+            self.status.set(JobStatus::Scheduled);
+        }
+    }
+
+    /// 等待到下一次触发，任务停止时提前返回 `false`
+    pub async fn wait(&self, dur: Duration) -> bool {
+        if self.is_stopped() {
+            return false;
+        }
+        tokio::select! {
+            _ = sleep(dur) => !self.is_stopped(),
+            _ = self.notify.notified() => false,
+        }
+    }
+}
+
+/// `Sched:add` 返回给 Lua 的任务句柄
+///
+/// `cancel` 只会停止之后的触发，正在执行的那一次会正常跑完。
+/// 暂停期间到点的触发会被跳过。
+#[derive(Clone)]
+pub struct Job(pub Rc<JobState>);
+
+impl Job {
+    pub fn new(options: &JobOptions) -> Self {
+        Job(Rc::new(JobState::new(options)))
+    }
+}
+
+impl LuaUserData for Job {
+    fn add_fields<'lua, F: LuaUserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("id", |_, this| Ok(this.0.id));
+        fields.add_field_method_get("name", |_, this| Ok(this.0.name.clone()));
+        fields.add_field_method_get("description", |_, this| Ok(this.0.description.clone()));
+        fields.add_field_method_get("priority", |_, this| Ok(this.0.priority));
+    }
+
+    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(_methods: &mut M) {
+        _methods.add_method("cancel", |_, this, ()| {
+            this.0.cancel();
+            Ok(())
+        });
+        _methods.add_method("pause", |_, this, ()| {
+            this.0.pause();
+            Ok(())
+        });
+        _methods.add_method("resume", |_, this, ()| {
+            this.0.resume();
+            Ok(())
+        });
+        _methods.add_method("next_run", |_, this, ()| {
+            Ok(this.0.next_run().map(|datetime| datetime.timestamp()))
+        });
+        _methods.add_method("is_running", |_, this, ()| Ok(this.0.is_running()));
+        _methods.add_method("status", |_, this, ()| Ok(this.0.status().as_str()));
+        _methods.add_method("finish_reason", |_, this, ()| {
+            Ok(this.0.finish_reason().map(|reason| reason.as_str()))
+        });
+        _methods.add_method("runs", |_, this, ()| Ok(this.0.runs()));
+        _methods.add_method("queued", |_, this, ()| Ok(this.0.queued()));
+        _methods.add_method("last_run", |lua, this, ()| {
+            this.0
+                .last_run()
+                .map(|record| record.to_table(lua))
+                .transpose()
+        });
+        _methods.add_method("history", |lua, this, ()| {
+            let history = lua.create_table()?;
+            for (i, record) in this.0.history().iter().enumerate() {
+                history.set(i + 1, record.to_table(lua)?)?;
+            }
+            Ok(history)
+        });
+    }
+}
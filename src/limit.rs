@@ -0,0 +1,131 @@
+use chrono::{DateTime, Utc};
+use std::{
+    cell::{Cell, RefCell},
+    cmp::Reverse,
+    rc::Rc,
+};
+use tokio::sync::Notify;
+
+/// `max_concurrent` 和 `group` 用的并发限制，排队时按优先级、触发时间、先来后到放行
+pub struct Limiter {
+    pub name: String,
+    pub limit: usize,
+    active: Cell<usize>,
+    next_ticket: Cell<u64>,
+    waiters: RefCell<Vec<Rc<Waiter>>>,
+}
+
+struct Waiter {
+    priority: i64,
+    scheduled_at: DateTime<Utc>,
+    ticket: u64,
+    granted: Cell<bool>,
+    notify: Notify,
+}
+
+/// 占用的名额，drop 时归还并放行下一个
+pub struct Permit(Rc<Limiter>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+/// 等待中的 acquire 被中止时把自己从队列里去掉，已经拿到的名额要还回去
+struct Pending<'a> {
+    limiter: &'a Rc<Limiter>,
+    waiter: Rc<Waiter>,
+    done: bool,
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        if self.waiter.granted.get() {
+            self.limiter.release();
+        } else {
+            let ticket = self.waiter.ticket;
+            self.limiter
+                .waiters
+                .borrow_mut()
+                .retain(|waiter| waiter.ticket != ticket);
+        }
+    }
+}
+
+impl Limiter {
+    pub fn new(name: String, limit: usize) -> Self {
+        Limiter {
+            name,
+            limit,
+            active: Cell::new(0),
+            next_ticket: Cell::new(0),
+            waiters: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// 有空闲名额并且没有人在排队时直接占用
+    pub fn try_acquire(self: &Rc<Self>) -> Option<Permit> {
+        if self.active.get() < self.limit && self.waiters.borrow().is_empty() {
+            self.active.set(self.active.get() + 1);
+            Some(Permit(self.clone()))
+        } else {
+            None
+        }
+    }
+
+    pub async fn acquire(self: &Rc<Self>, priority: i64, scheduled_at: DateTime<Utc>) -> Permit {
+        if let Some(permit) = self.try_acquire() {
+            return permit;
+        }
+        let ticket = self.next_ticket.get();
+        self.next_ticket.set(ticket + 1);
+        let waiter = Rc::new(Waiter {
+            priority,
+            scheduled_at,
+            ticket,
+            granted: Cell::new(false),
+            notify: Notify::new(),
+        });
+        self.waiters.borrow_mut().push(waiter.clone());
+        let mut pending = Pending {
+            limiter: self,
+            waiter,
+            done: false,
+        };
+        while !pending.waiter.granted.get() {
+            pending.waiter.notify.notified().await;
+        }
+        pending.done = true;
+        Permit(self.clone())
+    }
+
+    fn release(&self) {
+        let next = {
+            let mut waiters = self.waiters.borrow_mut();
+            let next = waiters
+                .iter()
+                .enumerate()
+                .max_by_key(|(_, waiter)| {
+                    (
+                        waiter.priority,
+                        Reverse(waiter.scheduled_at),
+                        Reverse(waiter.ticket),
+                    )
+                })
+                .map(|(i, _)| i);
+            next.map(|i| waiters.remove(i))
+        };
+        match next {
+            // 名额直接转给下一个，active 不变
+            Some(waiter) => {
+                waiter.granted.set(true);
+                waiter.notify.notify_one();
+            }
+            None => self.active.set(self.active.get() - 1),
+        }
+    }
+}
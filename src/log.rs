@@ -0,0 +1,13 @@
+/// 输出带时间和级别的日志到 stderr
+macro_rules! log {
+    ($level:literal, $($arg:tt)*) => {
+        eprintln!(
+            "{} [{}] {}",
+            chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+            $level,
+            format_args!($($arg)*)
+        )
+    };
+}
+
+pub(crate) use log;
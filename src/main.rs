@@ -1,15 +1,29 @@
+mod calendar;
+mod context;
 mod error;
+mod expr;
+mod hooks;
+mod job;
+mod limit;
+mod log;
 #[cfg(feature = "mysql")]
 mod mysql;
+mod options;
+mod runner;
 mod sched;
+mod store;
 #[cfg(feature = "time")]
 mod time; // 目前没什么用
+mod trigger;
+mod tz;
 
 use crate::error::Result;
+use crate::log::log;
 #[cfg(feature = "mysql")]
 use crate::mysql::create_mysql;
+use crate::runner::install_timeout_hook;
 use crate::sched::create_sched;
-use crate::sched::Sched;
+use crate::sched::{supervise, wait_signal, Sched, StopReason};
 use clap::Parser;
 use mlua::prelude::*;
 
@@ -23,24 +37,38 @@ struct Args {
 async fn main() -> Result<()> {
     let args = Args::parse();
     let lua = Lua::new();
+    install_timeout_hook(&lua)?;
 
     let globals = lua.globals();
     globals.set("sched", create_sched(&lua)?)?;
     #[cfg(feature = "mysql")]
-    globals.set("mysql", create_mysql(lua)?)?;
+    globals.set("mysql", create_mysql(&lua)?)?;
 
-    let file = tokio::fs::read_to_string(args.file)
+    let file = tokio::fs::read_to_string(&args.file)
         .await
         .expect("read file failed");
 
-    let handler: LuaAnyUserData = lua.load(&file).eval()?;
-    let tasks = handler.take::<Sched>()?;
-    let local = tokio::task::LocalSet::new();
-    for task in tasks.0 {
-        local.spawn_local(async move {
-            let _ = task.await;
-        });
+    let handler: LuaAnyUserData = lua
+        .load(&file)
+        .set_name(format!("@{}", args.file))?
+        .eval()?;
+    // 不能 take，任务执行时还可能通过脚本里的 sched 注册新任务
+    let tasks = handler.borrow_mut::<Sched>()?.start();
+    let mut local = tokio::task::LocalSet::new();
+    for task in tasks {
+        local.spawn_local(supervise(task));
     }
-    local.await;
-    Ok(())
+    // 所有任务都结束、收到退出信号，或者 `on_error = 'exit'` 的任务失败
+    let exit = handler.borrow::<Sched>()?.exit_request();
+    let reason = tokio::select! {
+        _ = &mut local => StopReason::Finished,
+        signal = wait_signal() => {
+            log!("INFO", "received {}, shutting down", signal);
+            StopReason::Signal(signal)
+        }
+        job = exit.wait() => StopReason::Failed(job),
+    };
+    let shutdown = handler.borrow_mut::<Sched>()?.shutdown();
+    let code = local.run_until(shutdown.run(&lua, reason)).await;
+    std::process::exit(code)
 }
@@ -24,7 +24,7 @@ macro_rules! row_to_table {
 }
 
 #[derive(Clone)]
-pub struct MysqlPool(Pool);
+pub struct MysqlPool(pub Pool);
 
 impl LuaUserData for MysqlPool {
     fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(_methods: &mut M) {
@@ -48,11 +48,9 @@ impl LuaUserData for MysqlPool {
             let mut conn: mysql_async::Conn = this.0.get_conn().await.to_lua_err()?;
 
             let rows: Vec<Row> = conn.query(sql).await.to_lua_err()?;
-            let mut i = 1;
-            for mut row in rows {
+            for (i, mut row) in (1..).zip(rows) {
                 let data: LuaTable = row_to_table!(row, lua);
                 query_data.set(i, data)?;
-                i += 1;
             }
 
             Ok(query_data)
@@ -75,11 +73,9 @@ impl LuaUserData for MysqlPool {
                 if params.is_empty() {
                     let query_data: LuaTable = lua.create_table()?;
                     let rows: Vec<Row> = conn.exec(sql, ()).await.to_lua_err()?;
-                    let mut i = 1;
-                    for mut row in rows {
+                    for (i, mut row) in (1..).zip(rows) {
                         let data: LuaTable = row_to_table!(row, lua);
                         query_data.set(i, data)?;
-                        i += 1;
                     }
                     return Ok(query_data);
                 }
@@ -99,11 +95,9 @@ impl LuaUserData for MysqlPool {
                 let rows: Vec<Row> = conn.exec(sql, new_params).await.to_lua_err()?;
 
                 let query_data: LuaTable = lua.create_table()?;
-                let mut i = 1;
-                for mut row in rows {
+                for (i, mut row) in (1..).zip(rows) {
                     let data: LuaTable = row_to_table!(row, lua);
                     query_data.set(i, data)?;
-                    i += 1;
                 }
                 Ok(query_data)
             },
@@ -174,7 +168,6 @@ impl LuaUserData for MysqlPool {
                 let mut conn: mysql_async::Conn = this.0.get_conn().await.to_lua_err()?;
                 if params.is_empty() {
                     Err(LuaError::ExternalError(Arc::new(WebError::new(
-                        6011,
                         "Parameter cannot be empty",
                     ))))
                 } else {
@@ -201,7 +194,6 @@ impl LuaUserData for MysqlPool {
                             }
                         } else {
                             return Err(LuaError::ExternalError(Arc::new(WebError::new(
-                                6012,
                                 "Parameter error",
                             ))));
                         }
@@ -214,7 +206,7 @@ impl LuaUserData for MysqlPool {
     }
 }
 
-fn mysql_value_to_lua_value(val: MysqlValue, lua: &Lua) -> LuaResult<LuaValue> {
+fn mysql_value_to_lua_value(val: MysqlValue, lua: &Lua) -> LuaResult<LuaValue<'_>> {
     match val {
         MysqlValue::NULL => {
             let data: Result<LuaString, LuaError> = lua.create_string("");
@@ -352,6 +344,6 @@ fn lua_value_to_mysql_value(val: LuaValue) -> MysqlValue {
     }
 }
 
-pub fn create_mysql(lua: &Lua) -> LuaResult<LuaAnyUserData> {
+pub fn create_mysql(lua: &Lua) -> LuaResult<LuaAnyUserData<'_>> {
     lua.create_proxy::<MysqlPool>()
 }
@@ -0,0 +1,613 @@
+use crate::calendar::Calendar;
+use crate::error::Error;
+use crate::limit::Limiter;
+use crate::store::Store;
+use crate::tz::resolve_local;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use mlua::prelude::*;
+use rand::Rng;
+use std::{cell::RefCell, collections::HashMap, fmt::Display, rc::Rc, time::Duration};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overlap {
+    /// 上一次还没跑完时跳过本次触发
+    Skip,
+    /// 排队等上一次跑完，最多排 `max_queue` 个
+    Queue,
+    /// 每次触发都单独起一个任务并发执行
+    Parallel,
+    /// 中止还在跑的，直接开始新的一次
+    Replace,
+}
+
+impl Overlap {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "skip" => Some(Overlap::Skip),
+            "queue" => Some(Overlap::Queue),
+            "parallel" => Some(Overlap::Parallel),
+            "replace" => Some(Overlap::Replace),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Overlap::Skip => "skip",
+            Overlap::Queue => "queue",
+            Overlap::Parallel => "parallel",
+            Overlap::Replace => "replace",
+        }
+    }
+}
+
+/// 触发时间已经过去（超过 `misfire_grace`）时的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Misfire {
+    /// 跳过错过的触发，只记录日志
+    Skip,
+    /// 所有错过的触发合并成一次执行
+    RunOnce,
+    /// 每个错过的触发都补跑一次，最多 `misfire_limit` 次
+    RunAll,
+}
+
+impl Misfire {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "skip" => Some(Misfire::Skip),
+            "run_once" => Some(Misfire::RunOnce),
+            "run_all" => Some(Misfire::RunAll),
+            _ => None,
+        }
+    }
+}
+
+/// 重试间隔的增长方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backoff {
+    /// 每次都等 `retry_delay`
+    Fixed,
+    /// 第 n 次重试等 `retry_delay * n`
+    Linear,
+    /// 第 n 次重试等 `retry_delay * 2^(n-1)`
+    Exponential,
+}
+
+impl Backoff {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "fixed" => Some(Backoff::Fixed),
+            "linear" => Some(Backoff::Linear),
+            "exponential" => Some(Backoff::Exponential),
+            _ => None,
+        }
+    }
+}
+
+/// 执行失败（重试之后仍然失败）后的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnError {
+    /// 记录日志，继续按计划触发
+    Continue,
+    /// 暂停任务，需要调用 `job:resume()` 恢复
+    Pause,
+    /// 结束整个进程
+    Exit,
+}
+
+impl OnError {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "continue" => Some(OnError::Continue),
+            "pause" => Some(OnError::Pause),
+            "exit" => Some(OnError::Exit),
+            _ => None,
+        }
+    }
+}
+
+/// `sched:every` 的间隔计算方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntervalMode {
+    /// 按固定频率触发，和上一次执行了多久无关
+    FixedRate,
+    /// 上一次执行结束之后再等一个间隔
+    FixedDelay,
+}
+
+impl IntervalMode {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "fixed_rate" => Some(IntervalMode::FixedRate),
+            "fixed_delay" => Some(IntervalMode::FixedDelay),
+            _ => None,
+        }
+    }
+}
+
+/// 触发时间落在日历里时的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnBlackout {
+    /// 直接跳过
+    Skip,
+    /// 顺延到下一个不被排除的日子的同一时刻
+    Shift,
+}
+
+impl OnBlackout {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "skip" => Some(OnBlackout::Skip),
+            "shift" => Some(OnBlackout::Shift),
+            _ => None,
+        }
+    }
+}
+
+/// 并发数已满时的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnLimit {
+    /// 等到有空闲名额
+    Wait,
+    /// 直接跳过，记为 skipped
+    Skip,
+}
+
+impl OnLimit {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "wait" => Some(OnLimit::Wait),
+            "skip" => Some(OnLimit::Skip),
+            _ => None,
+        }
+    }
+}
+
+/// `Sched:add` 第三个参数里的任务选项，脚本加载时就会校验
+pub struct JobOptions {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub timezone: Option<Tz>,
+    pub timeout: Option<Duration>,
+    pub retries: u32,
+    pub retry_delay: Duration,
+    pub backoff: Backoff,
+    pub retry_max_delay: Option<Duration>,
+    pub retry_jitter: Option<Duration>,
+    pub overlap: Overlap,
+    pub max_queue: usize,
+    pub jitter: Option<Duration>,
+    /// 按任务名和主机名固定偏移 0~spread
+    pub spread: Option<Duration>,
+    pub start_at: Option<DateTime<Utc>>,
+    pub end_at: Option<DateTime<Utc>>,
+    pub max_runs: Option<u64>,
+    pub misfire: Misfire,
+    pub misfire_grace: Duration,
+    pub misfire_limit: u32,
+    pub on_error: OnError,
+    /// `sched:every` 第一次触发前等待的时间，默认等一个间隔
+    pub delay: Option<Duration>,
+    pub mode: IntervalMode,
+    /// 启动时先执行一次
+    pub immediate: bool,
+    /// 上游任务名，全部成功后才执行
+    pub after: Vec<String>,
+    pub calendars: Vec<Rc<Calendar>>,
+    pub on_blackout: OnBlackout,
+    /// 执行前要拿到的并发名额，分组在前，全局的 `max_concurrent` 在后
+    pub limits: Vec<Rc<Limiter>>,
+    pub on_limit: OnLimit,
+    /// 名额不够时越大越先放行，相同时先触发的先放行
+    pub priority: i64,
+}
+
+impl Default for JobOptions {
+    fn default() -> Self {
+        JobOptions {
+            name: None,
+            description: None,
+            timezone: None,
+            timeout: None,
+            retries: 0,
+            retry_delay: Duration::from_secs(1),
+            backoff: Backoff::Fixed,
+            retry_max_delay: None,
+            retry_jitter: None,
+            overlap: Overlap::Skip,
+            max_queue: 1,
+            jitter: None,
+            spread: None,
+            start_at: None,
+            end_at: None,
+            max_runs: None,
+            misfire: Misfire::Skip,
+            misfire_grace: Duration::from_secs(1),
+            misfire_limit: 10,
+            on_error: OnError::Continue,
+            delay: None,
+            mode: IntervalMode::FixedRate,
+            immediate: false,
+            after: Vec::new(),
+            calendars: Vec::new(),
+            on_blackout: OnBlackout::Skip,
+            limits: Vec::new(),
+            on_limit: OnLimit::Wait,
+            priority: 0,
+        }
+    }
+}
+
+impl JobOptions {
+    pub fn from_table(table: Option<LuaTable>, config: &SchedConfig) -> LuaResult<Self> {
+        let mut options = JobOptions {
+            timezone: config.timezone,
+            timeout: config.timeout,
+            ..JobOptions::default()
+        };
+        let table = match table {
+            Some(table) => table,
+            None => return Ok(options),
+        };
+
+        // start_at / end_at 按任务时区解析，所以先处理 timezone
+        let timezone: LuaValue = table.get("timezone")?;
+        if timezone != LuaValue::Nil {
+            options.timezone = Some(as_timezone("timezone", timezone)?);
+        }
+
+        let mut group = None;
+        let mut limit = None;
+        for pair in table.pairs::<String, LuaValue>() {
+            let (key, value) = pair?;
+            match key.as_str() {
+                "name" => options.name = Some(as_string(&key, value)?),
+                "description" => options.description = Some(as_string(&key, value)?),
+                "timezone" => {}
+                "timeout" => options.timeout = Some(as_duration(&key, value)?),
+                "retries" => options.retries = as_integer(&key, value)?,
+                "retry_delay" => options.retry_delay = as_duration(&key, value)?,
+                "backoff" => {
+                    let backoff = as_string(&key, value)?;
+                    options.backoff = Backoff::parse(&backoff)
+                        .ok_or_else(|| invalid(&key, format!("unknown backoff `{backoff}`")))?;
+                }
+                "retry_max_delay" => options.retry_max_delay = Some(as_duration(&key, value)?),
+                "retry_jitter" => options.retry_jitter = Some(as_duration(&key, value)?),
+                "overlap" => {
+                    let mode = as_string(&key, value)?;
+                    options.overlap = Overlap::parse(&mode)
+                        .ok_or_else(|| invalid(&key, format!("unknown mode `{mode}`")))?;
+                }
+                "max_queue" => options.max_queue = as_integer(&key, value)?,
+                "jitter" => options.jitter = Some(as_offset(&key, value)?),
+                "spread" => options.spread = Some(as_offset(&key, value)?),
+                "start_at" => options.start_at = Some(as_datetime(&key, value, options.timezone)?),
+                "end_at" => options.end_at = Some(as_datetime(&key, value, options.timezone)?),
+                "max_runs" => options.max_runs = Some(as_integer(&key, value)?),
+                "misfire" => {
+                    let policy = as_string(&key, value)?;
+                    options.misfire = Misfire::parse(&policy)
+                        .ok_or_else(|| invalid(&key, format!("unknown policy `{policy}`")))?;
+                }
+                "misfire_grace" => options.misfire_grace = as_duration(&key, value)?,
+                "misfire_limit" => options.misfire_limit = as_integer(&key, value)?,
+                "on_error" => {
+                    let action = as_string(&key, value)?;
+                    options.on_error = OnError::parse(&action)
+                        .ok_or_else(|| invalid(&key, format!("unknown action `{action}`")))?;
+                }
+                "calendar" => {
+                    for name in as_names(&key, value)? {
+                        let calendar = config
+                            .calendars
+                            .get(&name)
+                            .ok_or_else(|| invalid(&key, format!("unknown calendar `{name}`")))?;
+                        options.calendars.push(calendar.clone());
+                    }
+                }
+                "on_blackout" => {
+                    let action = as_string(&key, value)?;
+                    options.on_blackout = OnBlackout::parse(&action)
+                        .ok_or_else(|| invalid(&key, format!("unknown action `{action}`")))?;
+                }
+                "group" => group = Some(as_string(&key, value)?),
+                "limit" => limit = Some(as_integer::<usize>(&key, value)?),
+                "on_limit" => {
+                    let action = as_string(&key, value)?;
+                    options.on_limit = OnLimit::parse(&action)
+                        .ok_or_else(|| invalid(&key, format!("unknown action `{action}`")))?;
+                }
+                "priority" => {
+                    options.priority = match value {
+                        LuaValue::Integer(n) => n,
+                        _ => return Err(invalid(&key, "expected an integer")),
+                    }
+                }
+                "after" => options.after = as_names(&key, value)?,
+                "immediate" => options.immediate = as_bool(&key, value)?,
+                "delay" => options.delay = Some(as_duration(&key, value)?),
+                "mode" => {
+                    let mode = as_string(&key, value)?;
+                    options.mode = IntervalMode::parse(&mode)
+                        .ok_or_else(|| invalid(&key, format!("unknown mode `{mode}`")))?;
+                }
+                _ => return Err(invalid(&key, "unknown option")),
+            }
+        }
+
+        match (group, limit) {
+            (None, Some(_)) => return Err(invalid("limit", "requires `group`")),
+            (_, Some(0)) => return Err(invalid("limit", "must be greater than zero")),
+            (Some(group), limit) => options.limits.push(config.group(group, limit)?),
+            (None, None) => {}
+        }
+        options.limits.extend(config.max_concurrent.clone());
+
+        if let (Some(start_at), Some(end_at)) = (options.start_at, options.end_at) {
+            if end_at <= start_at {
+                return Err(invalid("end_at", "must be later than start_at"));
+            }
+        }
+        if options.timeout == Some(Duration::ZERO) {
+            return Err(invalid("timeout", "must be greater than zero"));
+        }
+        if options.max_queue == 0 {
+            return Err(invalid("max_queue", "must be greater than zero"));
+        }
+        if options.misfire_limit == 0 {
+            return Err(invalid("misfire_limit", "must be greater than zero"));
+        }
+        if options.max_runs == Some(0) {
+            return Err(invalid("max_runs", "must be greater than zero"));
+        }
+        Ok(options)
+    }
+
+    /// 第 `retry` 次重试（从 1 开始）前等待的时间
+    pub fn retry_delay(&self, retry: u32) -> Duration {
+        let delay = match self.backoff {
+            Backoff::Fixed => self.retry_delay,
+            Backoff::Linear => self.retry_delay.saturating_mul(retry),
+            Backoff::Exponential => self
+                .retry_delay
+                .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1))),
+        };
+        let delay = match self.retry_max_delay {
+            Some(max_delay) => delay.min(max_delay),
+            None => delay,
+        };
+        match self.retry_jitter {
+            Some(jitter) => {
+                let millis = jitter.as_millis() as u64;
+                delay + Duration::from_millis(rand::thread_rng().gen_range(0..=millis))
+            }
+            None => delay,
+        }
+    }
+}
+
+/// `sched{...}` 的全局配置，作为各个任务选项的默认值
+#[derive(Default)]
+pub struct SchedConfig {
+    /// 未指定 `timezone` 的任务使用的时区，为空时使用本机时区
+    pub timezone: Option<Tz>,
+    /// 未指定 `timeout` 的任务使用的执行超时
+    pub timeout: Option<Duration>,
+    /// `sched:calendar` 注册的日历
+    pub calendars: HashMap<String, Rc<Calendar>>,
+    /// 所有任务加起来同时执行的上限
+    pub max_concurrent: Option<Rc<Limiter>>,
+    /// 按名字共享的分组并发限制，第一个用到的任务决定上限
+    pub groups: RefCell<HashMap<String, Rc<Limiter>>>,
+    /// 保存任务状态，重启之后补上错过的触发
+    pub store: Option<Rc<Store>>,
+    /// 收到退出信号后等正在执行的任务结束的最长时间
+    pub drain_timeout: Option<Duration>,
+}
+
+impl SchedConfig {
+    pub fn from_table(table: Option<LuaTable>) -> LuaResult<Self> {
+        let mut config = SchedConfig::default();
+        let table = match table {
+            Some(table) => table,
+            None => return Ok(config),
+        };
+        for pair in table.pairs::<String, LuaValue>() {
+            let (key, value) = pair?;
+            match key.as_str() {
+                "timezone" => config.timezone = Some(as_timezone(&key, value)?),
+                "timeout" => config.timeout = Some(as_duration(&key, value)?),
+                "max_concurrent" => {
+                    let limit: usize = as_integer(&key, value)?;
+                    if limit == 0 {
+                        return Err(invalid(&key, "must be greater than zero"));
+                    }
+                    config.max_concurrent =
+                        Some(Rc::new(Limiter::new("max_concurrent".to_string(), limit)));
+                }
+                "drain_timeout" => config.drain_timeout = Some(as_duration(&key, value)?),
+                "state" => config.store = Some(Rc::new(Store::from_lua(&key, value)?)),
+                _ => return Err(invalid(&key, "unknown option")),
+            }
+        }
+        if config.timeout == Some(Duration::ZERO) {
+            return Err(invalid("timeout", "must be greater than zero"));
+        }
+        Ok(config)
+    }
+
+    fn group(&self, name: String, limit: Option<usize>) -> LuaResult<Rc<Limiter>> {
+        let mut groups = self.groups.borrow_mut();
+        match (groups.get(&name), limit) {
+            (Some(group), Some(limit)) if group.limit != limit => Err(invalid(
+                "limit",
+                format!("group `{name}` already has limit {}", group.limit),
+            )),
+            (Some(group), _) => Ok(group.clone()),
+            (None, None) => Err(invalid(
+                "group",
+                format!("group `{name}` needs a `limit` the first time it is used"),
+            )),
+            (None, Some(limit)) => {
+                let group = Rc::new(Limiter::new(format!("group `{name}`"), limit));
+                groups.insert(name, group.clone());
+                Ok(group)
+            }
+        }
+    }
+}
+
+pub fn invalid<T: Display>(key: &str, message: T) -> LuaError {
+    Error::new(format!("invalid option `{key}`: {message}")).to_lua_err()
+}
+
+pub fn as_string(key: &str, value: LuaValue) -> LuaResult<String> {
+    match value {
+        LuaValue::String(s) => Ok(s.to_str()?.to_string()),
+        _ => Err(invalid(
+            key,
+            format!("expected string, got {}", value.type_name()),
+        )),
+    }
+}
+
+/// 一个名字或者名字数组，重复的名字只保留一个
+fn as_names(key: &str, value: LuaValue) -> LuaResult<Vec<String>> {
+    let values = match value {
+        LuaValue::Table(table) => table.sequence_values().collect::<LuaResult<_>>()?,
+        value => vec![value],
+    };
+    let mut names = Vec::new();
+    for value in values {
+        let name = as_string(key, value)?;
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    if names.is_empty() {
+        return Err(invalid(key, "expected at least one job name"));
+    }
+    Ok(names)
+}
+
+fn as_bool(key: &str, value: LuaValue) -> LuaResult<bool> {
+    match value {
+        LuaValue::Boolean(b) => Ok(b),
+        _ => Err(invalid(
+            key,
+            format!("expected boolean, got {}", value.type_name()),
+        )),
+    }
+}
+
+pub fn as_timezone(key: &str, value: LuaValue) -> LuaResult<Tz> {
+    let name = as_string(key, value)?;
+    name.parse::<Tz>()
+        .map_err(|_| invalid(key, format!("unknown timezone `{name}`")))
+}
+
+fn as_integer<T: TryFrom<i64>>(key: &str, value: LuaValue) -> LuaResult<T> {
+    let n = match value {
+        LuaValue::Integer(n) => n,
+        LuaValue::Number(n) if n.fract() == 0.0 => n as i64,
+        _ => return Err(invalid(key, "expected a non-negative integer")),
+    };
+    T::try_from(n).map_err(|_| invalid(key, "expected a non-negative integer"))
+}
+
+pub fn as_duration(key: &str, value: LuaValue) -> LuaResult<Duration> {
+    match value {
+        LuaValue::Integer(n) if n >= 0 => Ok(Duration::from_secs(n as u64)),
+        LuaValue::Number(n) if n.is_finite() && n >= 0.0 => Duration::try_from_secs_f64(n)
+            .map_err(|_| invalid(key, format!("duration `{n}` is too long"))),
+        LuaValue::String(s) => parse_duration(s.to_str()?).map_err(|e| invalid(key, e)),
+        _ => Err(invalid(
+            key,
+            "expected seconds or a duration string like `1m30s`",
+        )),
+    }
+}
+
+/// `jitter` / `spread` 的上限，太大时触发时间会超出能计算的范围
+const MAX_OFFSET: Duration = Duration::from_secs(366 * 24 * 60 * 60);
+
+fn as_offset(key: &str, value: LuaValue) -> LuaResult<Duration> {
+    let offset = as_duration(key, value)?;
+    if offset > MAX_OFFSET {
+        return Err(invalid(key, "must be at most 366d"));
+    }
+    Ok(offset)
+}
+
+pub fn as_datetime(key: &str, value: LuaValue, timezone: Option<Tz>) -> LuaResult<DateTime<Utc>> {
+    match value {
+        LuaValue::Integer(n) => Utc
+            .timestamp_opt(n, 0)
+            .single()
+            .ok_or_else(|| invalid(key, "timestamp out of range")),
+        LuaValue::String(s) => parse_datetime(s.to_str()?, timezone).map_err(|e| invalid(key, e)),
+        _ => Err(invalid(key, "expected a timestamp or a datetime string")),
+    }
+}
+
+/// 解析 `90`、`1.5`、`500ms`、`1h30m`、`2d` 这样的时长
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<f64>() {
+        if secs.is_finite() && secs >= 0.0 {
+            return Duration::try_from_secs_f64(secs)
+                .map_err(|_| format!("duration `{value}` is too long"));
+        }
+        return Err(format!("invalid duration `{value}`"));
+    }
+
+    let mut total = Duration::ZERO;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .ok_or_else(|| format!("missing unit in duration `{value}`"))?;
+        let number: f64 = rest[..digits]
+            .parse()
+            .map_err(|_| format!("invalid duration `{value}`"))?;
+        rest = &rest[digits..];
+        let unit_len = rest
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or(rest.len());
+        let unit = match &rest[..unit_len] {
+            "ms" => 0.001,
+            "s" => 1.0,
+            "m" => 60.0,
+            "h" => 3600.0,
+            "d" => 86400.0,
+            unit => return Err(format!("unknown unit `{unit}` in duration `{value}`")),
+        };
+        total = Duration::try_from_secs_f64(number * unit)
+            .ok()
+            .and_then(|part| total.checked_add(part))
+            .ok_or_else(|| format!("duration `{value}` is too long"))?;
+        rest = &rest[unit_len..];
+    }
+    Ok(total)
+}
+
+/// 解析 RFC 3339 或 `YYYY-MM-DD[ HH:MM[:SS]]`，不带偏移量的按任务时区处理
+pub fn parse_datetime(value: &str, timezone: Option<Tz>) -> Result<DateTime<Utc>, String> {
+    let value = value.trim();
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(value) {
+        return Ok(datetime.with_timezone(&Utc));
+    }
+    let naive = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+        })
+        .ok_or_else(|| format!("invalid datetime `{value}`"))?;
+    Ok(resolve_local(naive, timezone))
+}
@@ -0,0 +1,747 @@
+use crate::calendar::Exclusion;
+use crate::context::RunContext;
+use crate::error::Result;
+use crate::hooks::{Hook, Hooks};
+use crate::job::{FinishReason, JobState, RunRecord, RunStatus};
+use crate::log::log;
+use crate::options::{IntervalMode, JobOptions, Misfire, OnBlackout, OnError, OnLimit, Overlap};
+use crate::sched::ExitRequest;
+use crate::store::{JobRecord, Store};
+use crate::trigger::Trigger;
+use crate::tz::resolve_local;
+use chrono::{DateTime, Utc};
+use mlua::prelude::*;
+use rand::Rng;
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    future::Future,
+    pin::Pin,
+    rc::Rc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+/// 执行一个任务需要的全部东西，clone 之后交给单独的本地任务
+#[derive(Clone)]
+pub struct Runner {
+    pub lua: &'static Lua,
+    pub state: Rc<JobState>,
+    pub func: LuaFunction<'static>,
+    pub options: Rc<JobOptions>,
+    pub hooks: Rc<Hooks>,
+    pub exit: Rc<ExitRequest>,
+    pub store: Option<Rc<Store>>,
+    /// `immediate` 和 `@reboot` 那一次的触发时间，没有时用当前时间
+    pub startup: Rc<Cell<Option<DateTime<Utc>>>>,
+}
+
+impl Runner {
+    /// 任务的主循环，只负责按时触发，执行本身在单独的任务里进行
+    pub async fn run_schedule(self, trigger: Trigger) -> Result<()> {
+        let (state, options) = (&self.state, &self.options);
+        let grace = chrono::Duration::from_std(options.misfire_grace).to_lua_err()?;
+        let spread = match options.spread {
+            Some(spread) => {
+                chrono::Duration::from_std(spread_offset(&state.name, spread)).to_lua_err()?
+            }
+            None => chrono::Duration::zero(),
+        };
+        // 加上偏移之后还没到的触发也要算上
+        let mut after = Utc::now() - spread;
+        while let Some(datetime) = trigger.next(options.timezone, after) {
+            if datetime + spread > Utc::now() {
+                break;
+            }
+            after = datetime;
+        }
+        if let Trigger::Once(at) = trigger {
+            // 已经过去的时间也按 misfire 策略处理
+            after = after.min(at - chrono::Duration::milliseconds(1));
+        }
+        if let Some(last) = self.restore().await.and_then(|saved| saved.last_scheduled) {
+            // 上次运行之后错过的触发也按 misfire 策略处理
+            match &trigger {
+                Trigger::Cron(_) => after = after.min(last),
+                Trigger::Interval { interval, .. } => {
+                    trigger.restart(last + *interval);
+                    after = last;
+                }
+                Trigger::Once(at) if last >= *at => after = last,
+                Trigger::Once(_) | Trigger::Reboot => {}
+            }
+        }
+        if let Some(start_at) = options.start_at {
+            // after 是开区间
+            after = after.max(start_at - chrono::Duration::nanoseconds(1));
+        }
+        // `@reboot` 和 `immediate` 一样直接执行，不经过 misfire 判断
+        if (options.immediate || matches!(trigger, Trigger::Reboot))
+            && options
+                .start_at
+                .is_none_or(|start_at| start_at <= Utc::now())
+        {
+            self.dispatch(vec![self.startup.get().unwrap_or_else(Utc::now)]);
+        }
+        let mut reason = FinishReason::Completed;
+        let mut shifted = BTreeSet::new();
+        while let Some(datetime) = self.next_fire(&trigger, after, &mut shifted) {
+            after = datetime;
+            if state.is_stopped() {
+                break;
+            }
+            if options.end_at.is_some_and(|end_at| datetime > end_at) {
+                reason = FinishReason::EndAt;
+                break;
+            }
+            // 比如按固定间隔触发时，起点不一定落在 start_at 上
+            if options.start_at.is_some_and(|start_at| datetime < start_at) {
+                continue;
+            }
+            if Utc::now() - (datetime + spread) > grace {
+                after = self.misfire(&trigger, datetime, spread)?;
+                self.scheduled(after);
+                continue;
+            }
+            state.set_next_run(Some(datetime + spread));
+            let jitter = options.jitter.map_or(Duration::ZERO, |jitter| {
+                let millis = jitter.as_millis() as u64;
+                Duration::from_millis(rand::thread_rng().gen_range(0..=millis))
+            });
+            let due = datetime + spread + chrono::Duration::from_std(jitter).to_lua_err()?;
+            let dur = (due - Utc::now()).to_std().unwrap_or(Duration::ZERO);
+            if !state.wait(dur).await {
+                break;
+            }
+            self.scheduled(datetime);
+            if Utc::now() - due > grace {
+                // 进程被挂起之类的原因导致醒得太晚
+                after = self.misfire(&trigger, datetime, spread)?;
+                self.scheduled(after);
+            } else if !state.is_paused() {
+                self.dispatch(vec![datetime]);
+                if let (Trigger::Interval { interval, .. }, IntervalMode::FixedDelay) =
+                    (&trigger, options.mode)
+                {
+                    state.join_active().await;
+                    after = Utc::now();
+                    trigger.restart(after + *interval);
+                }
+            }
+        }
+        // 最后一次触发派发出去的执行跑完之后才算结束
+        state.join_active().await;
+        state.finish(reason);
+        Ok(())
+    }
+
+    /// 读取上次运行保存的状态
+    pub async fn restore(&self) -> Option<JobRecord> {
+        let saved = self.store.as_ref()?.get(&self.state.name).await?;
+        self.state.restore(saved.last_success);
+        Some(saved)
+    }
+
+    /// 这次触发已经处理过，重启之后不再补
+    fn scheduled(&self, datetime: DateTime<Utc>) {
+        if let Some(store) = &self.store {
+            store.scheduled(&self.state.name, datetime);
+        }
+    }
+
+    /// 任务挂的日历里排除 `datetime` 的第一个原因
+    fn excluded(&self, datetime: DateTime<Utc>) -> Option<Exclusion> {
+        self.options
+            .calendars
+            .iter()
+            .find_map(|calendar| calendar.excludes(datetime))
+    }
+
+    /// 跳过被日历排除的触发，`on_blackout = 'shift'` 时顺延的触发放在 `shifted` 里
+    fn next_fire(
+        &self,
+        trigger: &Trigger,
+        mut after: DateTime<Utc>,
+        shifted: &mut BTreeSet<DateTime<Utc>>,
+    ) -> Option<DateTime<Utc>> {
+        let options = &self.options;
+        loop {
+            let regular = trigger.next(options.timezone, after);
+            if let Some(&first) = shifted.first() {
+                if regular.is_none_or(|regular| first <= regular) {
+                    shifted.pop_first();
+                    return Some(first);
+                }
+            }
+            let datetime = regular?;
+            let exclusion = match self.excluded(datetime) {
+                Some(exclusion) => exclusion,
+                None => return Some(datetime),
+            };
+            // 同一段排除时间里的触发合并成一次处理
+            after = datetime.max(exclusion.until - chrono::Duration::nanoseconds(1));
+            match options.on_blackout {
+                OnBlackout::Skip => {
+                    log!(
+                        "INFO",
+                        "job `{}` skipped fire at {}: {}",
+                        self.state.name,
+                        datetime,
+                        exclusion.reason
+                    );
+                }
+                OnBlackout::Shift => match self.shift(datetime) {
+                    Some(target) => {
+                        log!(
+                            "INFO",
+                            "job `{}` shifted fire at {} to {}: {}",
+                            self.state.name,
+                            datetime,
+                            target,
+                            exclusion.reason
+                        );
+                        shifted.insert(target);
+                    }
+                    None => log!(
+                        "WARN",
+                        "job `{}` found no business day within a year after {}, skipped",
+                        self.state.name,
+                        datetime
+                    ),
+                },
+            }
+        }
+    }
+
+    /// 往后找第一个不被排除的日子，保持任务时区里的时刻不变
+    fn shift(&self, datetime: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let timezone = self.options.timezone;
+        let local = match timezone {
+            Some(tz) => datetime.with_timezone(&tz).naive_local(),
+            None => datetime.with_timezone(&chrono::Local).naive_local(),
+        };
+        (1..=366)
+            .map(|days| resolve_local(local + chrono::Duration::days(days), timezone))
+            .find(|candidate| self.excluded(*candidate).is_none())
+    }
+
+    /// 按 misfire 策略处理从 `first` 开始错过的触发，返回最后一个错过的触发时间（包括被日历排除的）
+    fn misfire(
+        &self,
+        trigger: &Trigger,
+        first: DateTime<Utc>,
+        spread: chrono::Duration,
+    ) -> Result<DateTime<Utc>> {
+        let (state, options) = (&self.state, &self.options);
+        let grace = chrono::Duration::from_std(options.misfire_grace).to_lua_err()?;
+        let deadline = Utc::now() - grace - spread;
+        let mut missed = vec![first];
+        let mut count = 1;
+        // `last` 是下一次从哪里接着算，`latest` 是最后一个没被日历排除的，只有它能拿来执行
+        let mut last = first;
+        let mut latest = first;
+        while let Some(datetime) = trigger.next(options.timezone, last) {
+            if datetime > deadline || options.end_at.is_some_and(|end_at| datetime > end_at) {
+                break;
+            }
+            last = datetime;
+            if self.excluded(datetime).is_some() {
+                continue;
+            }
+            count += 1;
+            latest = datetime;
+            if missed.len() < options.misfire_limit as usize {
+                missed.push(datetime);
+            }
+        }
+        if state.is_paused() {
+            return Ok(last);
+        }
+
+        match options.misfire {
+            Misfire::Skip => {
+                log!(
+                    "WARN",
+                    "job `{}` missed {} fire(s) since {}, skipped",
+                    state.name,
+                    count,
+                    first
+                );
+            }
+            Misfire::RunOnce => {
+                log!(
+                    "WARN",
+                    "job `{}` missed {} fire(s) since {}, running once",
+                    state.name,
+                    count,
+                    first
+                );
+                self.dispatch(vec![latest]);
+            }
+            Misfire::RunAll => {
+                log!(
+                    "WARN",
+                    "job `{}` missed {} fire(s) since {}, replaying {}",
+                    state.name,
+                    count,
+                    first,
+                    missed.len()
+                );
+                self.dispatch(missed);
+            }
+        }
+        Ok(last)
+    }
+
+    /// 按 overlap 策略执行，`fires` 里的触发会在同一个任务里依次执行
+    fn dispatch(&self, fires: Vec<DateTime<Utc>>) {
+        let (state, options) = (&self.state, &self.options);
+        match options.overlap {
+            Overlap::Skip if !state.is_idle() => {
+                self.skip(fires, "previous run is still active");
+                return;
+            }
+            Overlap::Queue if state.queued() >= options.max_queue => {
+                self.skip(fires, "queue is full");
+                return;
+            }
+            Overlap::Queue => state.set_queued(state.queued() + 1),
+            Overlap::Replace => state.abort_active(),
+            Overlap::Skip | Overlap::Parallel => {}
+        }
+
+        let runner = self.clone();
+        let handle = tokio::task::spawn_local(async move {
+            let state = &runner.state;
+            let _permit = if runner.options.overlap == Overlap::Queue {
+                let permit = state.slot().acquire().await;
+                state.set_queued(state.queued() - 1);
+                permit.ok()
+            } else {
+                None
+            };
+            for fire in fires {
+                if state.is_stopped() {
+                    break;
+                }
+                runner.run(fire).await;
+            }
+        });
+        state.track(handle);
+    }
+
+    fn skip(&self, fires: Vec<DateTime<Utc>>, reason: &str) {
+        for fire in fires {
+            let mut record = self.state.new_record(fire, self.options.overlap);
+            record.status = RunStatus::Skipped;
+            record.error = Some(reason.to_string());
+            self.state.run_finished(record);
+        }
+    }
+
+    /// 传给全局回调的上下文
+    fn context(&self, record: &RunRecord) -> LuaResult<LuaTable<'static>> {
+        let ctx = record.to_table(self.lua)?;
+        ctx.set("job", self.state.name.as_str())?;
+        ctx.set("job_id", self.state.id)?;
+        Ok(ctx)
+    }
+
+    async fn hook(&self, hook: Hook, record: &RunRecord) {
+        if self.hooks.is_empty(hook) {
+            return;
+        }
+        match self.context(record) {
+            Ok(ctx) => self.hooks.call(hook, &self.state.name, ctx).await,
+            Err(e) => log!("ERROR", "failed to build hook context: {}", e),
+        }
+    }
+
+    async fn run(&self, scheduled_at: DateTime<Utc>) {
+        let (state, options) = (&self.state, &self.options);
+        // 先拿并发名额，执行结束（包括被中止）时归还
+        let mut permits = Vec::with_capacity(options.limits.len());
+        let mut blocked = None;
+        let waiting_since = Instant::now();
+        for limiter in &options.limits {
+            if let Some(permit) = limiter.try_acquire() {
+                permits.push(permit);
+                continue;
+            }
+            if options.on_limit == OnLimit::Skip {
+                let reason = format!("concurrency limit {} reached", limiter.name);
+                log!("WARN", "job `{}` skipped: {}", state.name, reason);
+                let mut record = state.new_record(scheduled_at, options.overlap);
+                record.status = RunStatus::Skipped;
+                record.error = Some(reason);
+                record.limit = Some(limiter.name.clone());
+                state.run_finished(record);
+                return;
+            }
+            blocked.get_or_insert_with(|| limiter.name.clone());
+            permits.push(limiter.acquire(options.priority, scheduled_at).await);
+        }
+
+        let mut record = state.new_record(scheduled_at, options.overlap);
+        if blocked.is_some() {
+            record.limit = blocked;
+            record.waited = Some(waiting_since.elapsed());
+        }
+        // 等名额的时候任务被取消或者调度器开始退出
+        if state.is_stopped() {
+            record.status = RunStatus::Skipped;
+            record.error = Some("job stopped while waiting for a concurrency limit".to_string());
+            state.run_finished(record);
+            return;
+        }
+        let run_id = record.run_id;
+        let started_at = Utc::now();
+        record.started_at = Some(started_at);
+        record.attempts = 1;
+        let context = RunContext {
+            state: state.clone(),
+            run_id,
+            scheduled_at,
+            started_at,
+            attempt: 1,
+            last_success: state.last_success(),
+            deadline: None,
+        };
+        // 先建好 guard，在 before_run 回调里被中止也会记为 aborted
+        state.run_started();
+        let guard = RunGuard {
+            state: state.clone(),
+            record: Some(record.clone()),
+        };
+        self.hook(Hook::BeforeRun, &record).await;
+
+        let mut attempts = 1;
+        let mut result = self.call(context.clone()).await;
+        while let Err(e) = &result {
+            if attempts > options.retries {
+                break;
+            }
+            let delay = options.retry_delay(attempts);
+            log!(
+                "WARN",
+                "job `{}` run #{} attempt {} failed, retrying in {:?}: {}",
+                state.name,
+                run_id,
+                attempts,
+                delay,
+                e
+            );
+            if !state.wait(delay).await {
+                // 等待重试时任务被取消，不算失败，也不走 on_error
+                guard.abort(attempts, "job stopped during retry backoff");
+                return;
+            }
+            attempts += 1;
+            result = self
+                .call(RunContext {
+                    attempt: attempts,
+                    ..context.clone()
+                })
+                .await;
+        }
+        let record = guard.complete(&result, attempts);
+
+        if let Err(e) = &result {
+            log!(
+                "ERROR",
+                "job `{}` run #{} failed: {}",
+                state.name,
+                run_id,
+                e
+            );
+            self.hook(Hook::OnError, &record).await;
+        } else {
+            self.hook(Hook::OnSuccess, &record).await;
+        }
+        self.hook(Hook::AfterRun, &record).await;
+
+        if result.is_err() {
+            match options.on_error {
+                OnError::Continue => {}
+                OnError::Pause => {
+                    log!("WARN", "job `{}` paused after failure", state.name);
+                    state.pause();
+                }
+                OnError::Exit => {
+                    log!("ERROR", "job `{}` failed, shutting down", state.name);
+                    self.exit.request(&state.name);
+                }
+            }
+        } else if options
+            .max_runs
+            .is_some_and(|max_runs| state.runs() >= max_runs)
+        {
+            state.finish(FinishReason::MaxRuns);
+        }
+    }
+
+    async fn call(&self, mut context: RunContext) -> std::result::Result<Output, Failure> {
+        let func = &self.func;
+        // 超出 Instant 范围的超时和不限制一样
+        let timeout = self
+            .options
+            .timeout
+            .and_then(|limit| Some((limit, Instant::now().checked_add(limit)?)));
+        let (limit, deadline) = match timeout {
+            Some(timeout) => timeout,
+            None => {
+                return func
+                    .call_async::<_, LuaMultiValue>(context)
+                    .await
+                    .map(|values| self.output(values))
+                    .map_err(Failure::Error)
+            }
+        };
+        context.deadline = Some(deadline);
+        let fut = WithDeadline {
+            inner: Box::pin(func.call_async::<_, LuaMultiValue>(context)),
+            deadline,
+        };
+        match tokio::time::timeout(limit, fut).await {
+            Ok(Ok(values)) => Ok(self.output(values)),
+            // 纯 Lua 的死循环由指令计数 hook 打断，这里只能看到普通的 Lua 错误
+            Ok(Err(_)) if Instant::now() >= deadline => Err(Failure::TimedOut(limit)),
+            Ok(Err(e)) => Err(Failure::Error(e)),
+            Err(_) => Err(Failure::TimedOut(limit)),
+        }
+    }
+
+    /// 把返回值转成和 Lua 无关的形式保存，多个返回值放进数组，不能序列化的只记日志
+    fn output(&self, values: LuaMultiValue) -> Output {
+        let values = values
+            .into_iter()
+            .map(|value| self.lua.from_value::<serde_json::Value>(value))
+            .collect::<LuaResult<Vec<_>>>();
+        match values {
+            Ok(values) if values.len() > 1 => Some(serde_json::Value::Array(values)),
+            Ok(mut values) => values.pop().filter(|value| !value.is_null()),
+            Err(e) => {
+                log!(
+                    "WARN",
+                    "job `{}` returned a value that cannot be recorded: {}",
+                    self.state.name,
+                    e
+                );
+                None
+            }
+        }
+    }
+}
+
+/// 每个下游任务最多等待多少个还没凑齐上游结果的触发
+const PENDING_SIZE: usize = 64;
+
+/// 上游任务名和它那一次的执行结果
+type Upstream = (String, RunStatus);
+
+/// 通过 `after` 挂在上游任务后面的任务，没有自己的触发时间
+pub struct Dependent {
+    runner: Runner,
+    upstream: Vec<String>,
+    /// 按根触发时间收集上游的结果，所有上游都完成后才决定执行还是跳过
+    pending: RefCell<BTreeMap<DateTime<Utc>, Vec<Upstream>>>,
+}
+
+impl Dependent {
+    pub fn new(runner: Runner, upstream: Vec<String>) -> Self {
+        Dependent {
+            runner,
+            upstream,
+            pending: RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    /// 上游任务 `upstream` 的一次执行结束
+    pub fn notify(&self, upstream: &str, record: &RunRecord) {
+        let state = &self.runner.state;
+        if state.is_stopped() {
+            return;
+        }
+        let scheduled_at = record.scheduled_at;
+        let options = &self.runner.options;
+        if options.end_at.is_some_and(|end_at| scheduled_at > end_at) {
+            state.finish(FinishReason::EndAt);
+            return;
+        }
+        if options
+            .start_at
+            .is_some_and(|start_at| scheduled_at < start_at)
+        {
+            return;
+        }
+        let results = {
+            let mut pending = self.pending.borrow_mut();
+            let results = pending.entry(scheduled_at).or_default();
+            results.retain(|(name, _)| name != upstream);
+            results.push((upstream.to_string(), record.status));
+            if results.len() < self.upstream.len() {
+                if pending.len() > PENDING_SIZE {
+                    if let Some((dropped, results)) = pending.pop_first() {
+                        let missing: Vec<_> = self
+                            .upstream
+                            .iter()
+                            .filter(|name| results.iter().all(|(done, _)| done != *name))
+                            .map(|name| format!("`{name}`"))
+                            .collect();
+                        log!(
+                            "WARN",
+                            "job `{}` gave up waiting for {} at {}",
+                            state.name,
+                            missing.join(", "),
+                            dropped
+                        );
+                    }
+                }
+                return;
+            }
+            pending.remove(&scheduled_at).unwrap_or_default()
+        };
+        if state.is_paused() {
+            return;
+        }
+        match results
+            .iter()
+            .find(|(_, status)| *status != RunStatus::Success)
+        {
+            // 记成 skipped，再往下游传
+            Some((name, status)) => self.runner.skip(
+                vec![scheduled_at],
+                &format!("upstream `{}` {}", name, status.as_str()),
+            ),
+            None => self.runner.dispatch(vec![scheduled_at]),
+        }
+    }
+}
+
+/// 按任务名和主机名算出的固定偏移，同一台机器上的同一个任务每次都一样
+fn spread_offset(name: &str, spread: Duration) -> Duration {
+    let host = gethostname::gethostname();
+    // FNV-1a，不依赖标准库哈希算法的实现，升级编译器后结果也不会变
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in name
+        .as_bytes()
+        .iter()
+        .chain([0u8].iter())
+        .chain(host.as_encoded_bytes())
+    {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    let millis = spread.as_millis() as u64;
+    Duration::from_millis(hash % (millis + 1))
+}
+
+/// 任务函数的返回值
+type Output = Option<serde_json::Value>;
+
+/// 一次调用失败的原因
+enum Failure {
+    Error(LuaError),
+    TimedOut(Duration),
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Error(e) => write!(f, "{e}"),
+            Failure::TimedOut(limit) => write!(f, "timed out after {limit:?}"),
+        }
+    }
+}
+
+/// 正在执行的记录，被 `replace` 中止（future 被 drop）时记为 aborted
+struct RunGuard {
+    state: Rc<JobState>,
+    record: Option<RunRecord>,
+}
+
+impl RunGuard {
+    fn complete(
+        mut self,
+        result: &std::result::Result<Output, Failure>,
+        attempts: u32,
+    ) -> RunRecord {
+        let mut record = self.record.take().expect("run record already taken");
+        record.finished_at = Some(Utc::now());
+        record.attempts = attempts;
+        match result {
+            Ok(output) => {
+                record.status = RunStatus::Success;
+                record.result = output.clone();
+            }
+            Err(e) => {
+                record.status = match e {
+                    Failure::Error(_) => RunStatus::Failed,
+                    Failure::TimedOut(_) => RunStatus::TimedOut,
+                };
+                record.error = Some(e.to_string());
+            }
+        }
+        self.state.run_finished(record.clone());
+        record
+    }
+
+    /// 没有执行完就结束，记为 aborted
+    fn abort(mut self, attempts: u32, reason: &str) {
+        if let Some(mut record) = self.record.take() {
+            record.attempts = attempts;
+            record.error = Some(reason.to_string());
+            self.finish_aborted(record);
+        }
+    }
+
+    fn finish_aborted(&self, mut record: RunRecord) {
+        record.finished_at = Some(Utc::now());
+        record.status = RunStatus::Aborted;
+        self.state.run_finished(record);
+    }
+}
+
+impl Drop for RunGuard {
+    fn drop(&mut self) {
+        if let Some(record) = self.record.take() {
+            self.finish_aborted(record);
+        }
+    }
+}
+
+thread_local! {
+    /// 当前正在 poll 的那次执行的截止时间
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// poll 期间设置 `DEADLINE`，Lua 代码只会在 poll 里执行，所以 hook 看到的就是当前这次执行的截止时间
+struct WithDeadline<F> {
+    inner: Pin<Box<F>>,
+    deadline: Instant,
+}
+
+impl<F: Future> Future for WithDeadline<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let previous = DEADLINE.with(|deadline| deadline.replace(Some(self.deadline)));
+        let poll = self.inner.as_mut().poll(cx);
+        DEADLINE.with(|deadline| deadline.set(previous));
+        poll
+    }
+}
+
+/// 每执行一定数量的指令检查一次截止时间，用来打断不会让出的纯 Lua 循环
+pub fn install_timeout_hook(lua: &Lua) -> LuaResult<()> {
+    lua.set_hook(
+        LuaHookTriggers::every_nth_instruction(10_000),
+        |_, _| match DEADLINE.with(|deadline| deadline.get()) {
+            Some(deadline) if Instant::now() >= deadline => {
+                Err(LuaError::RuntimeError("run timed out".to_string()))
+            }
+            _ => Ok(()),
+        },
+    )
+}
@@ -1,40 +1,553 @@
-use crate::error::Result;
-use chrono::{Duration, Local};
-use cron::Schedule;
+use crate::calendar::Calendar;
+use crate::error::{Error, Result};
+use crate::expr::{self, Expression};
+use crate::hooks::{Hook, Hooks};
+use crate::job::Job;
+use crate::log::log;
+use crate::options::{
+    as_datetime, as_duration, as_timezone, invalid, JobOptions, Misfire, SchedConfig,
+};
+use crate::runner::{Dependent, Runner};
+use crate::store::Store;
+use crate::trigger::Trigger;
+use crate::tz::next_cron_fire;
+use chrono::{DateTime, Utc};
 use mlua::prelude::*;
-use std::{future::Future, pin::Pin, str::FromStr};
-use tokio::time::sleep;
+use std::{
+    cell::{Cell, RefCell},
+    future::Future,
+    pin::Pin,
+    rc::Rc,
+    time::Duration,
+};
+use tokio::sync::Notify;
 
-pub struct Sched(pub Vec<Pin<Box<dyn Future<Output = Result<()>>>>>);
+type Task = Pin<Box<dyn Future<Output = Result<()>>>>;
 
-pub fn create_sched(lua: &Lua) -> LuaResult<LuaFunction> {
-    lua.create_function(|_, ()| Ok(Sched(Vec::new())))
+pub struct Sched {
+    tasks: Vec<Task>,
+    /// 调度开始之后新建的任务直接放到当前的 LocalSet 里执行
+    started: bool,
+    /// 正在退出，不再接受新任务
+    stopping: bool,
+    config: SchedConfig,
+    hooks: Rc<Hooks>,
+    /// `on_error = 'exit'` 的任务失败时通知 main 退出
+    exit: Rc<ExitRequest>,
+    /// 所有注册过的任务，`after` 按名字在这里查找上游，`remove` 之后去掉
+    jobs: Vec<Job>,
+    /// 调度开始的时间，脚本加载期间注册的任务启动时执行的那一次都用它当触发时间，下游才能对上
+    startup: Rc<Cell<Option<DateTime<Utc>>>>,
+}
+
+/// 全局的 `sched`，可以当函数调用创建调度器，也带几个不需要调度器的工具函数
+pub fn create_sched(lua: &Lua) -> LuaResult<LuaTable<'_>> {
+    let sched = lua.create_table()?;
+    sched.set(
+        "next_runs",
+        lua.create_function(
+            |lua, (expression, n, timezone): (String, Option<usize>, Option<LuaValue>)| {
+                let timezone = match timezone {
+                    Some(LuaValue::Nil) | None => None,
+                    Some(timezone) => Some(as_timezone("timezone", timezone)?),
+                };
+                let runs = lua.create_table()?;
+                let expr = match expr::parse(&expression).map_err(Error::new).to_lua_err()? {
+                    Expression::Cron(expr) => expr,
+                    Expression::Reboot => return Ok(runs),
+                };
+                let mut after = Utc::now();
+                for i in 1..=n.unwrap_or(5).min(1000) {
+                    match next_cron_fire(&expr, timezone, after) {
+                        Some(datetime) => {
+                            runs.set(i, datetime.timestamp())?;
+                            after = datetime;
+                        }
+                        None => break,
+                    }
+                }
+                Ok(runs)
+            },
+        )?,
+    )?;
+    sched.set(
+        "describe",
+        lua.create_function(|_, expression: String| {
+            expr::describe(&expression).map_err(Error::new).to_lua_err()
+        })?,
+    )?;
+
+    let metatable = lua.create_table()?;
+    metatable.set(
+        "__call",
+        lua.create_function(|_, (_, config): (LuaTable, Option<LuaTable>)| {
+            Ok(Sched {
+                tasks: Vec::new(),
+                started: false,
+                stopping: false,
+                config: SchedConfig::from_table(config)?,
+                hooks: Rc::new(Hooks::default()),
+                exit: Rc::new(ExitRequest::default()),
+                jobs: Vec::new(),
+                startup: Rc::default(),
+            })
+        })?,
+    )?;
+    sched.set_metatable(Some(metatable));
+    Ok(sched)
+}
+
+impl Sched {
+    /// 取出脚本加载期间注册的任务，之后注册的任务会立即开始调度
+    pub fn start(&mut self) -> Vec<Task> {
+        self.started = true;
+        self.startup.set(Some(Utc::now()));
+        std::mem::take(&mut self.tasks)
+    }
+
+    pub fn exit_request(&self) -> Rc<ExitRequest> {
+        self.exit.clone()
+    }
+
+    /// 停止所有任务之后的触发，正在执行的交给 `Shutdown` 收尾
+    pub fn shutdown(&mut self) -> Shutdown {
+        self.stopping = true;
+        for job in &self.jobs {
+            job.0.cancel();
+        }
+        Shutdown {
+            jobs: std::mem::take(&mut self.jobs),
+            hooks: self.hooks.clone(),
+            store: self.config.store.clone(),
+            drain_timeout: self.config.drain_timeout.unwrap_or(DRAIN_TIMEOUT),
+        }
+    }
+
+    fn spawn(&mut self, task: Task) {
+        if self.started {
+            tokio::task::spawn_local(supervise(task));
+        } else {
+            self.tasks.push(task);
+        }
+    }
+
+    fn runner(
+        &self,
+        lua: &Lua,
+        func: LuaFunction,
+        options: JobOptions,
+    ) -> LuaResult<(Job, Runner)> {
+        if self.stopping {
+            return Err(Error::new("scheduler is shutting down")).to_lua_err();
+        }
+        let lua: &'static Lua = unsafe { std::mem::transmute(lua) };
+        let func: LuaFunction<'static> = unsafe { std::mem::transmute(func) };
+        let job = Job::new(&options);
+        // 只保存设置了名字的任务，自动生成的名字在脚本改动之后会对不上
+        let store = options.name.as_ref().and(self.config.store.clone());
+        if let Some(store) = &store {
+            let store = store.clone();
+            let name = job.0.name.clone();
+            job.0.on_finished(
+                job.0.id,
+                Box::new(move |record| store.finished(&name, record)),
+            );
+        }
+        let runner = Runner {
+            lua,
+            state: job.0.clone(),
+            func,
+            options: Rc::new(options),
+            hooks: self.hooks.clone(),
+            exit: self.exit.clone(),
+            store,
+            // 调度开始之后注册的任务各自用当前时间
+            startup: if self.started {
+                Rc::default()
+            } else {
+                self.startup.clone()
+            },
+        };
+        Ok((job, runner))
+    }
+
+    fn push(
+        &mut self,
+        lua: &Lua,
+        trigger: Trigger,
+        func: LuaFunction,
+        options: JobOptions,
+    ) -> LuaResult<Job> {
+        if !options.after.is_empty() {
+            return Err(invalid(
+                "after",
+                "jobs with upstream jobs are added with sched:add(fn, options)",
+            ));
+        }
+        self.prune();
+        self.check_name(&options)?;
+        let (job, runner) = self.runner(lua, func, options)?;
+        self.spawn(Box::pin(runner.run_schedule(trigger)));
+        self.jobs.push(job.clone());
+        Ok(job)
+    }
+
+    /// 在上游任务全部成功之后执行的任务，上游必须先注册，所以不会成环
+    fn push_dependent(
+        &mut self,
+        lua: &Lua,
+        func: LuaFunction,
+        options: JobOptions,
+    ) -> LuaResult<Job> {
+        if options.after.is_empty() {
+            return Err(invalid("after", "required when no schedule is given"));
+        }
+        self.prune();
+        let upstream = options
+            .after
+            .iter()
+            .map(|name| {
+                self.find(name)
+                    .cloned()
+                    .ok_or_else(|| invalid("after", format!("unknown job `{name}`")))
+            })
+            .collect::<LuaResult<Vec<_>>>()?;
+        self.check_name(&options)?;
+        let names = options.after.clone();
+        let (job, runner) = self.runner(lua, func, options)?;
+        if runner.store.is_some() {
+            let runner = runner.clone();
+            self.spawn(Box::pin(async move {
+                runner.restore().await;
+                Ok(())
+            }));
+        }
+        let dependent = Rc::new(Dependent::new(runner, names));
+        for upstream in upstream {
+            let dependent = dependent.clone();
+            let name = upstream.0.name.clone();
+            upstream.0.on_finished(
+                job.0.id,
+                Box::new(move |record| dependent.notify(&name, record)),
+            );
+        }
+        self.jobs.push(job.clone());
+        Ok(job)
+    }
+
+    /// 还在调度的任务不能重名，否则 `remove` 和 `after` 没法按名字找
+    fn check_name(&self, options: &JobOptions) -> LuaResult<()> {
+        match &options.name {
+            Some(name) if self.find(name).is_some() => {
+                Err(invalid("name", format!("job `{name}` already exists")))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn find(&self, name: &str) -> Option<&Job> {
+        self.jobs
+            .iter()
+            .find(|job| job.0.name == name && !job.0.is_stopped())
+    }
+
+    /// 取消任务并从调度器里去掉，正在执行的那一次会正常跑完
+    fn remove(&mut self, name: &str) -> LuaResult<bool> {
+        let job = match self.find(name) {
+            Some(job) => job.clone(),
+            None => return Ok(false),
+        };
+        let downstream: Vec<_> = self
+            .jobs
+            .iter()
+            .filter(|job| !job.0.is_stopped() && job.0.after.iter().any(|after| after == name))
+            .map(|job| format!("`{}`", job.0.name))
+            .collect();
+        if !downstream.is_empty() {
+            return Err(Error::new(format!(
+                "job `{name}` is required by {}",
+                downstream.join(", ")
+            )))
+            .to_lua_err();
+        }
+        job.0.cancel();
+        self.detach(&job);
+        self.prune();
+        log!("INFO", "job `{}` removed", name);
+        Ok(true)
+    }
+
+    /// 去掉已经停止、也没有在执行的任务，正在执行的留到下次，退出时还要等它们
+    fn prune(&mut self) {
+        let (stopped, jobs): (Vec<_>, Vec<_>) = std::mem::take(&mut self.jobs)
+            .into_iter()
+            .partition(|job| job.0.is_stopped() && job.0.is_idle());
+        self.jobs = jobs;
+        for job in &stopped {
+            self.detach(job);
+        }
+    }
+
+    /// 去掉下游任务挂在上游上的监听
+    fn detach(&self, job: &Job) {
+        for upstream in &self.jobs {
+            if job.0.after.contains(&upstream.0.name) {
+                upstream.0.remove_listeners(job.0.id);
+            }
+        }
+    }
 }
 
 impl LuaUserData for Sched {
     fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(_methods: &mut M) {
         _methods.add_method_mut(
             "add",
-            |_, this, (expression, func): (String, LuaFunction)| {
-                let func: LuaFunction<'static> = unsafe { std::mem::transmute(func) };
-                let schedule = Schedule::from_str(&expression).to_lua_err()?;
-                let mut scheduler = schedule.upcoming_owned(Local);
-                let zero = Duration::zero();
-                let fut = async move {
-                    while let Some(datetime) = scheduler.next() {
-                        let now = Local::now();
-                        let dur = datetime - now;
-                        if dur > zero {
-                            let dur = dur.to_std().to_lua_err()?;
-                            sleep(dur).await;
-                            func.call_async(()).await.to_lua_err()?;
-                        }
-                    }
-                    Ok(())
+            |lua, this, (first, second, options): (LuaValue, LuaValue, Option<LuaTable>)| {
+                // sched:add(fn, { after = ... }) 由上游任务触发
+                if let LuaValue::Function(func) = first {
+                    let options = Option::<LuaTable>::from_lua(second, lua)?;
+                    let options = JobOptions::from_table(options, &this.config)?;
+                    return this.push_dependent(lua, func, options);
+                }
+                let expression = String::from_lua(first, lua)?;
+                let func = LuaFunction::from_lua(second, lua)?;
+                let trigger = match expr::parse(&expression).map_err(Error::new).to_lua_err()? {
+                    Expression::Cron(expr) => Trigger::Cron(expr),
+                    Expression::Reboot => Trigger::Reboot,
                 };
-                this.0.push(Box::pin(fut));
-                Ok(())
+                let options = JobOptions::from_table(options, &this.config)?;
+                this.push(lua, trigger, func, options)
             },
         );
+        _methods.add_method_mut(
+            "every",
+            |lua, this, (interval, func, options): (LuaValue, LuaFunction, Option<LuaTable>)| {
+                let interval = as_duration("interval", interval)?;
+                if interval < Duration::from_millis(1) {
+                    return Err(invalid("interval", "must be at least 1ms"));
+                }
+                let options = JobOptions::from_table(options, &this.config)?;
+                let interval = chrono::Duration::from_std(interval).to_lua_err()?;
+                let delay = match options.delay {
+                    Some(delay) => chrono::Duration::from_std(delay).to_lua_err()?,
+                    None => interval,
+                };
+                let first = Utc::now()
+                    .checked_add_signed(delay)
+                    .ok_or_else(|| invalid("delay", "too long"))?;
+                let trigger = Trigger::interval(interval, first);
+                this.push(lua, trigger, func, options)
+            },
+        );
+        _methods.add_method_mut(
+            "at",
+            |lua, this, (at, func, options): (LuaValue, LuaFunction, Option<LuaTable>)| {
+                let options = once_options(options, &this.config)?;
+                let at = as_datetime("at", at, options.timezone)?;
+                this.push(lua, Trigger::Once(at), func, options)
+            },
+        );
+        _methods.add_method_mut(
+            "after",
+            |lua, this, (delay, func, options): (LuaValue, LuaFunction, Option<LuaTable>)| {
+                let delay = as_duration("delay", delay)?;
+                let options = once_options(options, &this.config)?;
+                let at = chrono::Duration::from_std(delay)
+                    .ok()
+                    .and_then(|delay| Utc::now().checked_add_signed(delay))
+                    .ok_or_else(|| invalid("delay", "too long"))?;
+                this.push(lua, Trigger::Once(at), func, options)
+            },
+        );
+        _methods.add_method_mut("remove", |_, this, name: String| this.remove(&name));
+        _methods.add_method("jobs", |lua, this, ()| {
+            let jobs = lua.create_table()?;
+            for job in this.jobs.iter().filter(|job| !job.0.is_stopped()) {
+                jobs.set(job.0.name.as_str(), job.clone())?;
+            }
+            Ok(jobs)
+        });
+        _methods.add_method_mut("calendar", |_, this, (name, spec): (String, LuaTable)| {
+            let calendar = Calendar::from_table(name.clone(), spec, this.config.timezone)?;
+            this.config.calendars.insert(name, Rc::new(calendar));
+            Ok(())
+        });
+        for hook in [
+            Hook::BeforeRun,
+            Hook::AfterRun,
+            Hook::OnSuccess,
+            Hook::OnError,
+            Hook::OnShutdown,
+        ] {
+            _methods.add_method(hook.as_str(), move |_, this, func: LuaFunction| {
+                let func: LuaFunction<'static> = unsafe { std::mem::transmute(func) };
+                this.hooks.add(hook, func);
+                Ok(())
+            });
+        }
+    }
+}
+
+/// 一次性任务晚了默认还是执行一次，显式指定了 `misfire` 时按指定的
+fn once_options(table: Option<LuaTable>, config: &SchedConfig) -> LuaResult<JobOptions> {
+    let explicit = match &table {
+        Some(table) => table.contains_key("misfire")?,
+        None => false,
+    };
+    let mut options = JobOptions::from_table(table, config)?;
+    if !explicit {
+        options.misfire = Misfire::RunOnce;
+    }
+    Ok(options)
+}
+
+/// 没有配置 `drain_timeout` 时等正在执行的任务结束的时间
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// 调度器为什么要退出
+pub enum StopReason {
+    /// 所有任务都已经结束
+    Finished,
+    Signal(&'static str),
+    /// `on_error = 'exit'` 的任务失败了
+    Failed(String),
+}
+
+/// `on_error = 'exit'` 的任务失败后通知 main 走正常的退出流程，只记第一个失败的任务
+#[derive(Default)]
+pub struct ExitRequest {
+    job: RefCell<Option<String>>,
+    notify: Notify,
+}
+
+impl ExitRequest {
+    pub fn request(&self, job: &str) {
+        self.job.borrow_mut().get_or_insert_with(|| job.to_string());
+        self.notify.notify_one();
+    }
+
+    /// 等到有任务请求退出，返回任务名
+    pub async fn wait(&self) -> String {
+        self.notify.notified().await;
+        self.job.borrow().clone().unwrap_or_default()
+    }
+}
+
+/// 退出时的收尾工作，不借用 `Sched`，收尾期间任务里仍然可以调用 `sched` 的方法
+pub struct Shutdown {
+    jobs: Vec<Job>,
+    hooks: Rc<Hooks>,
+    store: Option<Rc<Store>>,
+    drain_timeout: Duration,
+}
+
+impl Shutdown {
+    /// 等正在执行的任务结束，超时就中止，然后调用 `on_shutdown` 回调、写回状态，返回退出码
+    ///
+    /// 由 `on_error = 'exit'` 引起的退出码总是 1。
+    pub async fn run(self, lua: &Lua, reason: StopReason) -> i32 {
+        // 回调都是 'static 的，和 `runner` 一样
+        let lua: &'static Lua = unsafe { std::mem::transmute(lua) };
+        let running = self.jobs.iter().filter(|job| !job.0.is_idle()).count();
+        if running > 0 {
+            log!(
+                "INFO",
+                "waiting up to {:?} for {} running job(s)",
+                self.drain_timeout,
+                running
+            );
+        }
+        let drained = tokio::select! {
+            result = tokio::time::timeout(self.drain_timeout, self.drain()) => result.is_ok(),
+            signal = wait_signal() => {
+                log!("WARN", "received {} again, not waiting any longer", signal);
+                false
+            }
+        };
+        let mut aborted = Vec::new();
+        if !drained {
+            for job in self.jobs.iter().filter(|job| !job.0.is_idle()) {
+                aborted.push(job.0.name.clone());
+                job.0.abort_active();
+            }
+            log!("WARN", "aborted running job(s): {}", aborted.join(", "));
+            // 让被中止的执行记下 aborted
+            self.drain().await;
+        }
+
+        let mut ok = aborted.is_empty() && !matches!(reason, StopReason::Failed(_));
+        match self.context(lua, &reason, &aborted) {
+            Ok(ctx) => ok &= self.hooks.shutdown(ctx).await,
+            Err(e) => log!("ERROR", "failed to build shutdown context: {}", e),
+        }
+        if let Some(store) = &self.store {
+            if let Err(e) = store.close().await {
+                log!("ERROR", "failed to save scheduler state: {}", e);
+                ok = false;
+            }
+        }
+        log!("INFO", "scheduler stopped");
+        if ok {
+            0
+        } else {
+            1
+        }
+    }
+
+    async fn drain(&self) {
+        while self.jobs.iter().any(|job| !job.0.is_idle()) {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    fn context<'lua>(
+        &self,
+        lua: &'lua Lua,
+        reason: &StopReason,
+        aborted: &[String],
+    ) -> LuaResult<LuaTable<'lua>> {
+        let ctx = lua.create_table()?;
+        match reason {
+            StopReason::Finished => {}
+            StopReason::Signal(signal) => ctx.set("signal", *signal)?,
+            StopReason::Failed(job) => ctx.set("job", job.as_str())?,
+        }
+        ctx.set(
+            "aborted",
+            lua.create_sequence_from(aborted.iter().cloned())?,
+        )?;
+        Ok(ctx)
+    }
+}
+
+/// 等待 SIGINT 或 SIGTERM，返回信号名
+pub async fn wait_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = match signal(SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(e) => {
+                log!("ERROR", "failed to listen for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+                return "SIGINT";
+            }
+        };
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => "SIGINT",
+            _ = terminate.recv() => "SIGTERM",
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        "SIGINT"
+    }
+}
+
+/// 任务循环出错时记录日志
+pub async fn supervise(task: Task) {
+    if let Err(e) = task.await {
+        log!("ERROR", "scheduler loop exited: {}", e);
     }
 }
@@ -0,0 +1,314 @@
+use crate::error::{Error, Result};
+use crate::job::{RunRecord, RunStatus};
+use crate::log::log;
+use crate::options::{as_string, invalid};
+use chrono::{DateTime, Utc};
+use mlua::prelude::*;
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeSet, HashMap},
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::Duration,
+};
+use tokio::sync::OnceCell;
+
+/// 一个任务保存下来的状态，重启之后据此补上错过的触发
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JobRecord {
+    /// 最后一次处理过的触发时间，执行、跳过都算
+    pub last_scheduled: Option<DateTime<Utc>>,
+    /// 最后一次执行结束的时间
+    pub last_completed: Option<DateTime<Utc>>,
+    /// 最后一次成功执行的触发时间
+    pub last_success: Option<DateTime<Utc>>,
+}
+
+impl JobRecord {
+    fn to_json(self) -> serde_json::Value {
+        let format =
+            |datetime: Option<DateTime<Utc>>| datetime.map(|datetime| datetime.to_rfc3339());
+        serde_json::json!({
+            "last_scheduled": format(self.last_scheduled),
+            "last_completed": format(self.last_completed),
+            "last_success": format(self.last_success),
+        })
+    }
+
+    fn from_json(value: &serde_json::Value) -> Self {
+        let parse = |key: &str| {
+            value[key]
+                .as_str()
+                .and_then(|datetime| DateTime::parse_from_rfc3339(datetime).ok())
+                .map(|datetime| datetime.with_timezone(&Utc))
+        };
+        JobRecord {
+            last_scheduled: parse("last_scheduled"),
+            last_completed: parse("last_completed"),
+            last_success: parse("last_success"),
+        }
+    }
+}
+
+enum Backend {
+    /// 整个状态存成一个 JSON 文件
+    File(PathBuf),
+    /// 每个任务一行，表不存在时自动创建
+    #[cfg(feature = "mysql")]
+    Mysql {
+        pool: mysql_async::Pool,
+        table: String,
+    },
+}
+
+/// 全局配置 `state` 指定的状态存储，只保存设置了 `name` 的任务
+pub struct Store {
+    backend: Backend,
+    jobs: RefCell<HashMap<String, JobRecord>>,
+    /// 第一次用到时才读取，MySQL 要等到调度开始之后才能访问
+    loaded: OnceCell<()>,
+    /// 还没写回的任务
+    dirty: RefCell<BTreeSet<String>>,
+    /// 后台正在写回
+    flushing: Cell<bool>,
+}
+
+impl Store {
+    /// `state = 'state.json'` 或者 `state = { file = ... }` / `state = { mysql = pool, table = ... }`
+    pub fn from_lua(key: &str, value: LuaValue) -> LuaResult<Self> {
+        let backend = match value {
+            LuaValue::String(_) => Backend::File(PathBuf::from(as_string(key, value)?)),
+            LuaValue::Table(table) => {
+                let file: Option<String> = table.get("file")?;
+                #[cfg(feature = "mysql")]
+                if let Some(pool) = table.get::<_, Option<LuaAnyUserData>>("mysql")? {
+                    let pool = pool.borrow::<crate::mysql::MysqlPool>()?.0.clone();
+                    let table = table
+                        .get::<_, Option<String>>("table")?
+                        .unwrap_or_else(|| "lua_scheduler_state".to_string());
+                    if !table.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                        return Err(invalid(key, format!("invalid table name `{table}`")));
+                    }
+                    return Ok(Store::new(Backend::Mysql { pool, table }));
+                }
+                match file {
+                    Some(file) => Backend::File(PathBuf::from(file)),
+                    None => return Err(invalid(key, "expected `file` or `mysql`")),
+                }
+            }
+            _ => {
+                return Err(invalid(
+                    key,
+                    format!("expected a file path or table, got {}", value.type_name()),
+                ))
+            }
+        };
+        Ok(Store::new(backend))
+    }
+
+    fn new(backend: Backend) -> Self {
+        Store {
+            backend,
+            jobs: RefCell::new(HashMap::new()),
+            loaded: OnceCell::new(),
+            dirty: RefCell::new(BTreeSet::new()),
+            flushing: Cell::new(false),
+        }
+    }
+
+    /// 上次运行时保存的状态，读取失败时当作没有
+    pub async fn get(&self, name: &str) -> Option<JobRecord> {
+        self.loaded
+            .get_or_init(|| async {
+                match self.load().await {
+                    Ok(jobs) => *self.jobs.borrow_mut() = jobs,
+                    Err(e) => log!("ERROR", "failed to load scheduler state: {}", e),
+                }
+            })
+            .await;
+        self.jobs.borrow().get(name).copied()
+    }
+
+    /// 调度循环处理完一次触发
+    pub fn scheduled(self: &Rc<Self>, name: &str, datetime: DateTime<Utc>) {
+        self.update(name, |job| {
+            job.last_scheduled = job.last_scheduled.max(Some(datetime));
+        });
+    }
+
+    /// 一次执行结束，跳过的触发也会经过这里
+    pub fn finished(self: &Rc<Self>, name: &str, record: &RunRecord) {
+        self.update(name, |job| {
+            job.last_scheduled = job.last_scheduled.max(Some(record.scheduled_at));
+            if record.started_at.is_some() {
+                job.last_completed = record.finished_at;
+            }
+            if record.status == RunStatus::Success {
+                job.last_success = Some(record.scheduled_at);
+            }
+        });
+    }
+
+    /// 有变化就在后台写回，同一时间只有一个写回在进行
+    fn update(self: &Rc<Self>, name: &str, update: impl FnOnce(&mut JobRecord)) {
+        update(self.jobs.borrow_mut().entry(name.to_string()).or_default());
+        self.dirty.borrow_mut().insert(name.to_string());
+        if self.flushing.replace(true) {
+            return;
+        }
+        let store = self.clone();
+        tokio::task::spawn_local(async move {
+            while !store.dirty.borrow().is_empty() {
+                if let Err(e) = store.flush().await {
+                    // 下次有变化时再试
+                    log!("ERROR", "failed to save scheduler state: {}", e);
+                    break;
+                }
+            }
+            store.flushing.set(false);
+        });
+    }
+
+    /// 退出前等后台的写回结束，再把剩下的写回去
+    pub async fn close(&self) -> Result<()> {
+        while self.flushing.get() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        self.flush().await
+    }
+
+    /// 把还没写回的状态写回去
+    async fn flush(&self) -> Result<()> {
+        let dirty = std::mem::take(&mut *self.dirty.borrow_mut());
+        if dirty.is_empty() {
+            return Ok(());
+        }
+        let result = self.save(&dirty).await;
+        if result.is_err() {
+            // 下次再试
+            self.dirty.borrow_mut().extend(dirty);
+        }
+        result
+    }
+
+    async fn load(&self) -> Result<HashMap<String, JobRecord>> {
+        match &self.backend {
+            Backend::File(path) => {
+                let content = match tokio::fs::read_to_string(path).await {
+                    Ok(content) => content,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+                    Err(e) => return Err(Error::new(format!("{}: {e}", path.display()))),
+                };
+                let value: serde_json::Value = serde_json::from_str(&content)
+                    .map_err(|e| Error::new(format!("{}: {e}", path.display())))?;
+                let jobs = value
+                    .as_object()
+                    .ok_or_else(|| Error::new(format!("{}: expected an object", path.display())))?;
+                Ok(jobs
+                    .iter()
+                    .map(|(name, job)| (name.clone(), JobRecord::from_json(job)))
+                    .collect())
+            }
+            #[cfg(feature = "mysql")]
+            Backend::Mysql { pool, table } => {
+                use mysql_async::prelude::Queryable;
+                let mut conn = pool.get_conn().await?;
+                conn.query_drop(format!(
+                    "CREATE TABLE IF NOT EXISTS `{table}` (
+                        job VARCHAR(255) NOT NULL PRIMARY KEY,
+                        last_scheduled BIGINT NULL,
+                        last_completed BIGINT NULL,
+                        last_success BIGINT NULL
+                    )"
+                ))
+                .await?;
+                // 任务名和三个毫秒时间戳
+                type Row = (String, Option<i64>, Option<i64>, Option<i64>);
+                let rows: Vec<Row> = conn
+                    .query(format!(
+                        "SELECT job, last_scheduled, last_completed, last_success FROM `{table}`"
+                    ))
+                    .await?;
+                let millis = |millis: Option<i64>| millis.and_then(DateTime::from_timestamp_millis);
+                Ok(rows
+                    .into_iter()
+                    .map(|(name, scheduled, completed, success)| {
+                        let job = JobRecord {
+                            last_scheduled: millis(scheduled),
+                            last_completed: millis(completed),
+                            last_success: millis(success),
+                        };
+                        (name, job)
+                    })
+                    .collect())
+            }
+        }
+    }
+
+    #[cfg_attr(not(feature = "mysql"), allow(unused_variables))]
+    async fn save(&self, dirty: &BTreeSet<String>) -> Result<()> {
+        match &self.backend {
+            Backend::File(path) => self.save_file(path).await,
+            #[cfg(feature = "mysql")]
+            Backend::Mysql { pool, table } => self.save_mysql(pool, table, dirty).await,
+        }
+    }
+
+    /// 文件每次整个重写
+    async fn save_file(&self, path: &Path) -> Result<()> {
+        let jobs: serde_json::Map<_, _> = self
+            .jobs
+            .borrow()
+            .iter()
+            .map(|(name, job)| (name.clone(), job.to_json()))
+            .collect();
+        let content = serde_json::to_string_pretty(&jobs).map_err(|e| Error::new(e.to_string()))?;
+        // 先写临时文件再改名，写到一半退出也不会损坏原来的文件
+        let tmp = path.with_extension("tmp");
+        tokio::fs::write(&tmp, content)
+            .await
+            .map_err(|e| Error::new(format!("{}: {e}", tmp.display())))?;
+        tokio::fs::rename(&tmp, path)
+            .await
+            .map_err(|e| Error::new(format!("{}: {e}", path.display())))
+    }
+
+    /// 只写有变化的任务
+    #[cfg(feature = "mysql")]
+    async fn save_mysql(
+        &self,
+        pool: &mysql_async::Pool,
+        table: &str,
+        dirty: &BTreeSet<String>,
+    ) -> Result<()> {
+        use mysql_async::prelude::Queryable;
+        let rows: Vec<_> = {
+            let jobs = self.jobs.borrow();
+            dirty
+                .iter()
+                .filter_map(|name| jobs.get(name).map(|job| (name.clone(), *job)))
+                .collect()
+        };
+        let millis =
+            |datetime: Option<DateTime<Utc>>| datetime.map(|datetime| datetime.timestamp_millis());
+        let mut conn = pool.get_conn().await?;
+        conn.exec_batch(
+            format!(
+                "INSERT INTO `{table}` (job, last_scheduled, last_completed, last_success)
+                VALUES (?, ?, ?, ?)
+                ON DUPLICATE KEY UPDATE last_scheduled = VALUES(last_scheduled),
+                last_completed = VALUES(last_completed), last_success = VALUES(last_success)"
+            ),
+            rows.into_iter().map(|(name, job)| {
+                (
+                    name,
+                    millis(job.last_scheduled),
+                    millis(job.last_completed),
+                    millis(job.last_success),
+                )
+            }),
+        )
+        .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,53 @@
+use crate::expr::CronExpr;
+use crate::tz::next_cron_fire;
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use std::cell::Cell;
+
+/// 决定任务什么时候触发
+pub enum Trigger {
+    Cron(Box<CronExpr>),
+    /// `sched:every`，在 `anchor + k * interval` 触发
+    Interval {
+        interval: chrono::Duration,
+        anchor: Cell<DateTime<Utc>>,
+    },
+    /// `sched:at` / `sched:after`，只触发一次
+    Once(DateTime<Utc>),
+    /// `@reboot`，调度开始时执行一次，没有其它触发
+    Reboot,
+}
+
+impl Trigger {
+    pub fn interval(interval: chrono::Duration, first: DateTime<Utc>) -> Self {
+        Trigger::Interval {
+            interval,
+            anchor: Cell::new(first),
+        }
+    }
+
+    /// `after` 之后（不含）的下一次触发时间
+    pub fn next(&self, timezone: Option<Tz>, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Trigger::Cron(expr) => next_cron_fire(expr, timezone, after),
+            Trigger::Interval { interval, anchor } => {
+                let anchor = anchor.get();
+                if after < anchor {
+                    return Some(anchor);
+                }
+                let step = interval.num_milliseconds().max(1);
+                let elapsed = (after - anchor).num_milliseconds();
+                Some(anchor + chrono::Duration::milliseconds((elapsed / step + 1) * step))
+            }
+            Trigger::Once(at) => (after < *at).then_some(*at),
+            Trigger::Reboot => None,
+        }
+    }
+
+    /// fixed_delay 模式下上一次执行结束后重新计算起点
+    pub fn restart(&self, first: DateTime<Utc>) {
+        if let Trigger::Interval { anchor, .. } = self {
+            anchor.set(first);
+        }
+    }
+}
@@ -0,0 +1,135 @@
+//! 时区和夏令时处理
+//!
+//! cron 表达式按任务时区的本地时间（墙上时间）求值，遇到夏令时切换时：
+//! - 被跳过的本地时间（例如 02:30 在 02:00 → 03:00 的切换里不存在）在跳变结束后的第一个有效时刻触发一次；
+//! - 重复出现的本地时间（例如 02:30 在 03:00 → 02:00 的切换里出现两次）只在第一次出现时触发。
+
+use crate::expr::CronExpr;
+use chrono::{DateTime, Duration, Local, LocalResult, NaiveDateTime, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+
+/// 按上面的夏令时策略把本地时间转换成 UTC，`timezone` 为空时使用本机时区
+pub fn resolve_local(naive: NaiveDateTime, timezone: Option<Tz>) -> DateTime<Utc> {
+    match timezone {
+        Some(tz) => resolve_in(&tz, naive),
+        None => resolve_in(&Local, naive),
+    }
+}
+
+/// `after` 之后（不含）的下一次触发时间
+pub fn next_cron_fire(
+    expr: &CronExpr,
+    timezone: Option<Tz>,
+    after: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    match timezone {
+        Some(tz) => next_in(expr, &tz, after),
+        None => next_in(expr, &Local, after),
+    }
+}
+
+fn resolve_in<Z: TimeZone>(tz: &Z, naive: NaiveDateTime) -> DateTime<Utc> {
+    match tz.from_local_datetime(&naive) {
+        LocalResult::Single(datetime) | LocalResult::Ambiguous(datetime, _) => {
+            datetime.with_timezone(&Utc)
+        }
+        LocalResult::None => {
+            // 落在跳变区间里，往后找第一个存在的整分钟
+            let mut candidate = naive.with_second(0).unwrap_or(naive);
+            loop {
+                candidate += Duration::minutes(1);
+                if let Some(datetime) = tz.from_local_datetime(&candidate).earliest() {
+                    return datetime.with_timezone(&Utc);
+                }
+            }
+        }
+    }
+}
+
+fn next_in<Z: TimeZone>(expr: &CronExpr, tz: &Z, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    // 在本地时间上计算，这样不会被夏令时切换影响
+    let mut naive = after.with_timezone(tz).naive_local();
+    loop {
+        let candidate = expr.after(naive)?;
+        let datetime = resolve_in(tz, candidate);
+        if datetime > after {
+            return Some(datetime);
+        }
+        naive = candidate;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expr::{parse, Expression};
+    use chrono_tz::Europe::Berlin;
+
+    fn utc(value: &str) -> DateTime<Utc> {
+        NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M")
+            .unwrap()
+            .and_utc()
+    }
+
+    fn local(value: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    /// `after` 之后的 `count` 次触发，UTC
+    fn fires(expression: &str, after: &str, count: usize) -> Vec<DateTime<Utc>> {
+        let expr = match parse(expression) {
+            Ok(Expression::Cron(expr)) => expr,
+            _ => panic!("`{expression}` is not a cron expression"),
+        };
+        let mut after = utc(after);
+        (0..count)
+            .map(|_| {
+                after = next_cron_fire(&expr, Some(Berlin), after).unwrap();
+                after
+            })
+            .collect()
+    }
+
+    #[test]
+    fn skipped_local_time_resolves_to_end_of_gap() {
+        // 2027-03-28 02:00 CET 跳到 03:00 CEST，也就是 01:00 UTC
+        assert_eq!(
+            resolve_local(local("2027-03-28 02:30"), Some(Berlin)),
+            utc("2027-03-28 01:00")
+        );
+    }
+
+    #[test]
+    fn repeated_local_time_resolves_to_first_occurrence() {
+        // 2026-10-25 03:00 CEST 回到 02:00 CET，02:30 先是 CEST（00:30 UTC）再是 CET（01:30 UTC）
+        assert_eq!(
+            resolve_local(local("2026-10-25 02:30"), Some(Berlin)),
+            utc("2026-10-25 00:30")
+        );
+    }
+
+    #[test]
+    fn skipped_fire_runs_once_after_the_gap() {
+        assert_eq!(
+            fires("0 30 2 * * *", "2027-03-27 12:00", 2),
+            [utc("2027-03-28 01:00"), utc("2027-03-29 00:30")]
+        );
+    }
+
+    #[test]
+    fn repeated_fire_runs_only_on_first_occurrence() {
+        assert_eq!(
+            fires("0 30 2 * * *", "2026-10-24 12:00", 2),
+            [utc("2026-10-25 00:30"), utc("2026-10-26 01:30")]
+        );
+        // 整点的任务在重复的那一个小时里也只触发一次
+        assert_eq!(
+            fires("0 0 * * * *", "2026-10-24 23:30", 3),
+            [
+                utc("2026-10-25 00:00"),
+                utc("2026-10-25 02:00"),
+                utc("2026-10-25 03:00"),
+            ]
+        );
+    }
+}